@@ -3,15 +3,15 @@ use ruffle_gc::{pin_root, Gc, GcContext, GcHeapRoot};
 fn main() {
     let mut ctx = GcContext::new().unwrap();
 
-    let root = GcHeapRoot::new(Node::new(&mut ctx));
+    let root = GcHeapRoot::new(&mut ctx, Node::new);
 
     {
         let a = Node::new(&mut ctx);
-        pin_root!(a);
+        pin_root!(ctx, a);
         root.set_next(&mut ctx, Some(*a));
 
         let b = Node::new(&mut ctx);
-        pin_root!(b);
+        pin_root!(ctx, b);
         a.set_next(&mut ctx, Some(*b));
         b.set_next(&mut ctx, Some(*a));
     }
@@ -3,8 +3,7 @@ use ruffle_gc::{pin_root, Gc, GcContext, GcHeapRoot, Trace};
 fn main() {
     let mut ctx = GcContext::new().unwrap();
 
-    let list: List<i32> = List::new(&mut ctx);
-    let list = GcHeapRoot::new(list);
+    let list = GcHeapRoot::new(&mut ctx, List::<i32>::new);
     for i in 0..10 {
         list.push_front(&mut ctx, i);
     }
@@ -28,13 +27,13 @@ impl<'a, T: Trace> List<'a, T> {
 
     fn push_front(self, ctx: &mut GcContext, value: T) {
         let prev_head = self.0.borrow(ctx).head;
-        pin_root!(prev_head);
+        pin_root!(ctx, prev_head);
         let new_head = Node(ctx.allocate(NodeData {
             value,
             prev: None,
             next: *prev_head,
         }));
-        pin_root!(new_head);
+        pin_root!(ctx, new_head);
         new_head.0.borrow_mut(ctx).next = *prev_head;
         self.0.borrow_mut(ctx).head = Some(*new_head);
         if let Some(prev_head) = *prev_head {
@@ -47,10 +46,10 @@ impl<'a, T: Trace> List<'a, T> {
         T: Clone,
     {
         let head = self.0.borrow(ctx).head;
-        pin_root!(head);
+        pin_root!(ctx, head);
         if let Some(head) = *head {
             let new_head = head.0.borrow(ctx).next;
-            pin_root!(new_head);
+            pin_root!(ctx, new_head);
             if let Some(new_head) = *new_head {
                 new_head.0.borrow_mut(ctx).prev = None;
             }
@@ -5,7 +5,7 @@ fn main() {
 
     {
         let object = Object::new(&mut ctx, "My Object".to_string(), 42);
-        pin_root!(object);
+        pin_root!(ctx, object);
 
         ctx.collect();
         println!(
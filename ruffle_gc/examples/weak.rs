@@ -2,15 +2,15 @@ use ruffle_gc::{pin_root, Gc, GcContext, GcHeapRoot, GcWeak};
 
 fn main() {
     let mut ctx = GcContext::new().unwrap();
-    let object = GcHeapRoot::new(Object::new(&mut ctx, "Test"));
+    let object = GcHeapRoot::new(&mut ctx, |ctx| Object::new(ctx, "Test"));
     {
         let object2 = Object::new(&mut ctx, "Weak");
-        pin_root!(object2);
+        pin_root!(ctx, object2);
         let weak = object2.0.downgrade(&ctx);
         object.0.borrow_mut(&mut ctx).next = Some(weak);
     };
     let weak = object.0.borrow(&ctx).next;
-    //pin_root!(weak);
+    //pin_root!(ctx, weak);
     println!(
         "{:?}",
         weak.and_then(|weak| weak.borrow(&ctx)).map(|obj| &obj.name)
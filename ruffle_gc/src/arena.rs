@@ -0,0 +1,123 @@
+use crate::{Gc, GcContext, GcHeapRoot, GcLifetime, GcRoot, Trace};
+use std::{
+    mem::{self, ManuallyDrop},
+    ops::Deref,
+};
+
+/// A context with a single root, whose managed data is only accessed during mutation sessions.
+///
+/// Collections only happen between calls to `mutate`, so nothing allocated during a session needs
+/// to be pinned with `pin_root!`. Anything that should outlive the session just has to be
+/// reachable from the root by the time it ends. `R` is the type of the root with any lifetime,
+/// such as `Root<'static>`, and sessions see it as `Root<'gc>`.
+pub struct GcArena<R> {
+    // The root must be dropped before the context is destroyed.
+    root: ManuallyDrop<GcHeapRoot<R>>,
+    ctx: ManuallyDrop<GcContext>,
+}
+
+/// Access to the managed data of a `GcArena` for the duration of a mutation session.
+///
+/// This derefs to the arena's `GcContext`, so it can be passed to `Gc::borrow`, `Gc::write` and
+/// `GcWeak::upgrade`. `Gc::borrow_mut` needs exclusive access to the context, so data is mutated
+/// through a `GcCell` with `Gc::write` instead.
+pub struct GcMutation<'gc> {
+    ctx: &'gc GcContext,
+}
+
+type Error = Box<dyn std::error::Error>;
+
+impl<R> GcArena<R>
+where
+    R: for<'gc> GcLifetime<'gc> + Trace,
+{
+    /// Creates an arena with a new context, rooting the value returned by `f`.
+    pub fn new<F>(f: F) -> Result<Self, Error>
+    where
+        F: for<'gc> FnOnce(&GcMutation<'gc>) -> <R as GcLifetime<'gc>>::Aged,
+    {
+        let ctx = GcContext::new()?;
+        unsafe {
+            let value = f(&GcMutation { ctx: &ctx });
+            // `R` is the same type as the value, with a different lifetime.
+            let value = ManuallyDrop::new(value);
+            let value: R = mem::transmute_copy(&*value);
+            let root = GcHeapRoot::register(ctx.data(), GcRoot::with_vtbl(value, R::vtbl()));
+            Ok(Self {
+                root: ManuallyDrop::new(root),
+                ctx: ManuallyDrop::new(ctx),
+            })
+        }
+    }
+
+    /// Runs a mutation session, calling `f` with access to the root.
+    pub fn mutate<F, T>(&mut self, f: F) -> T
+    where
+        F: for<'gc> FnOnce(&GcMutation<'gc>, &'gc <R as GcLifetime<'gc>>::Aged) -> T,
+    {
+        unsafe {
+            let root = &*(&**self.root as *const R as *const <R as GcLifetime<'_>>::Aged);
+            f(&GcMutation { ctx: &self.ctx }, root)
+        }
+    }
+
+    /// Performs a full collection. See `GcContext::collect`.
+    pub fn collect(&mut self) {
+        self.ctx.collect();
+    }
+
+    /// Performs incremental collection work. See `GcContext::collect_step`.
+    pub fn collect_step(&mut self, work_budget: usize) {
+        self.ctx.collect_step(work_budget);
+    }
+
+    /// Performs collection work if enough memory has been allocated since the last call. This is
+    /// meant to be called between sessions. See `GcContext::collect_if_needed`.
+    pub fn collect_if_needed(&mut self) {
+        self.ctx.collect_if_needed();
+    }
+
+    /// Returns the arena's context, such as for reading its statistics.
+    pub fn context(&self) -> &GcContext {
+        &self.ctx
+    }
+
+    /// Returns the arena's context, such as for changing its settings. Any roots created with it
+    /// must be dropped before the arena is.
+    pub fn context_mut(&mut self) -> &mut GcContext {
+        &mut self.ctx
+    }
+}
+
+impl<R> Drop for GcArena<R> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.root);
+            ManuallyDrop::take(&mut self.ctx).destroy();
+        }
+    }
+}
+
+impl<'gc> GcMutation<'gc> {
+    /// Allocates a value for the rest of the session. It is collected afterwards unless it is
+    /// reachable from the root.
+    pub fn allocate<T>(&self, value: T) -> Gc<'gc, T::Aged>
+    where
+        T: GcLifetime<'gc> + Trace,
+    {
+        self.ctx.allocate_without_collecting(value, T::vtbl())
+    }
+
+    /// Returns the arena's context for the rest of the session.
+    pub fn context(&self) -> &'gc GcContext {
+        self.ctx
+    }
+}
+
+impl Deref for GcMutation<'_> {
+    type Target = GcContext;
+
+    fn deref(&self) -> &GcContext {
+        self.ctx
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{Gc, GcContext, GcData, GcLifetime, Trace};
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    fmt::{self, Debug},
+};
+
+/// A value in managed data that can be mutated with shared access to the `GcContext`, such as
+/// during a `GcArena` mutation session.
+///
+/// Managed data can't otherwise contain a `Cell` or `RefCell`: storing a pointer into an object
+/// needs a write barrier, and `Gc::borrow` doesn't apply one. A `GcCell` can only be read through
+/// a shared reference, and is written to through a `Gc<GcCell<T>>` with `Gc::write` or `Gc::set`,
+/// which apply the barrier to the cell's object.
+pub struct GcCell<T>(RefCell<T>);
+
+impl<T> GcCell<T> {
+    pub fn new(value: T) -> Self {
+        Self(RefCell::new(value))
+    }
+
+    /// Immutably borrows the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed by `Gc::write`.
+    #[track_caller]
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    /// Returns a copy of the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed by `Gc::write`.
+    #[track_caller]
+    pub fn get(&self) -> T
+    where
+        T: Copy,
+    {
+        *self.0.borrow()
+    }
+
+    /// Mutably borrows the value. This needs no barrier, since exclusive access to the cell
+    /// requires `Gc::borrow_mut`, which applies one.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+impl<'a, T> Gc<'a, GcCell<T>> {
+    /// Mutably borrows the value in the cell, with shared access to the `GcContext`.
+    ///
+    /// A write barrier is applied so that an in-progress collection will see any pointers stored
+    /// into the value. The barrier stays in effect for as long as the returned reference is alive,
+    /// since no collection work can happen in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is already borrowed.
+    #[track_caller]
+    pub fn write<'b>(self, ctx: &'b GcContext) -> RefMut<'b, T::Aged>
+    where
+        T: GcLifetime<'b>,
+        'a: 'b,
+    {
+        unsafe {
+            GcData::assert_live(self.ptr);
+            ctx.write_barrier(self.ptr);
+            (*(*(self.ptr as *mut GcData<GcCell<T::Aged>>)).value.get())
+                .0
+                .borrow_mut()
+        }
+    }
+
+    /// Replaces the value in the cell, applying a write barrier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    #[track_caller]
+    pub fn set<'b>(self, ctx: &'b GcContext, value: T::Aged)
+    where
+        T: GcLifetime<'b>,
+        'a: 'b,
+    {
+        *self.write(ctx) = value;
+    }
+}
+
+impl<T: Default> Default for GcCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Debug> Debug for GcCell<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("GcCell").field(&self.0).finish()
+    }
+}
+
+unsafe impl<T: Trace> Trace for GcCell<T> {
+    unsafe fn trace(&self, ctx: &mut GcContext) {
+        self.0.borrow().trace(ctx)
+    }
+
+    unsafe fn trace_fields(&self, ctx: &mut GcContext) {
+        self.0.borrow().trace_fields(ctx)
+    }
+}
+
+unsafe impl<'a, T> GcLifetime<'a> for GcCell<T>
+where
+    T: GcLifetime<'a>,
+{
+    type Aged = GcCell<T::Aged>;
+}
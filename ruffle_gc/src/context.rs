@@ -1,65 +1,532 @@
-use crate::{Gc, GcData, GcDataPtr, GcFlags, GcLifetime, GcRootData, GcWeak, Trace, WeakId};
+use crate::{
+    heap::{Heap, Page},
+    Cleanup, CleanupFn, Ephemeron, Finalize, Gc, GcCleanupToken, GcCollection, GcData, GcDataPtr,
+    GcFlags, GcHeapSnapshot, GcLifetime, GcObjectEntry, GcObjectInfo, GcObjectSnapshot, GcObserver,
+    GcPathHop, GcRetainingPath, GcRootData, GcRootInfo, GcRootKind, GcVtbl, GcWeak, GcWeakId,
+    Trace, WeakId, WeakValues,
+};
 use generational_arena::Arena;
-use once_cell::unsync::OnceCell;
-use std::{cell::UnsafeCell, marker::PhantomData};
-
-thread_local! {
-    static CONTEXT: OnceCell<*mut GcContextData> = OnceCell::new();
-}
+use std::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    num::NonZeroU32,
+    rc::{self, Rc},
+    time::{Duration, Instant},
+};
 
+/// A garbage collected heap.
+///
+/// Any number of contexts may exist at once, even on the same thread, but managed data and roots
+/// belong to the context that created them and must not be mixed between contexts.
 #[derive(Debug)]
 pub struct GcContext(*mut GcContextData);
 
+/// The phase of an incremental collection cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcPhase {
+    /// No collection cycle is in progress.
+    Idle,
+    /// Reachable objects are being traced.
+    Marking,
+    /// Unreachable objects are being deallocated.
+    Sweeping,
+}
+
+/// Tuning parameters for automatic collection via `GcContext::collect_if_needed`.
+///
+/// These work like the parameters of Lua's incremental collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcPacing {
+    /// How large the heap may grow before a new cycle starts, as a percentage of its size at the
+    /// end of the previous cycle. The default of 200 waits for the heap to double.
+    pub pause: u32,
+    /// How many objects are traced or swept for each object allocated while a cycle is in
+    /// progress, as a percentage. Higher values finish cycles sooner with longer pauses.
+    pub step_multiplier: u32,
+    /// The number of bytes that must be allocated between incremental steps.
+    pub step_size: usize,
+}
+
+impl Default for GcPacing {
+    fn default() -> Self {
+        Self {
+            pause: 200,
+            step_multiplier: 200,
+            step_size: 8 * 1024,
+        }
+    }
+}
+
+/// Configuration for generational collection.
+///
+/// When enabled, new objects are allocated into a nursery which can be collected on its own by a
+/// cheap minor collection. Objects that survive enough minor collections are promoted to the old
+/// generation, which is only collected by a full (major) collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcNursery {
+    /// The number of bytes allocated into the nursery before `collect_if_needed` performs a minor
+    /// collection.
+    pub size: usize,
+    /// The number of minor collections an object must survive before it is promoted, between 1
+    /// and 7.
+    pub promotion_age: u8,
+}
+
+impl Default for GcNursery {
+    fn default() -> Self {
+        Self {
+            size: 256 * 1024,
+            promotion_age: 2,
+        }
+    }
+}
+
+/// Statistics about the heap and the collections performed on it, returned by `GcContext::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GcStats {
+    /// The number of major collections that have finished.
+    pub collections: u32,
+    /// The number of minor collections that have finished.
+    pub minor_collections: u32,
+    /// The number of managed objects.
+    pub live_objects: usize,
+    /// The total size in bytes of all managed data, including object headers.
+    pub live_bytes: usize,
+    /// The largest `live_bytes` has ever been.
+    pub peak_bytes: usize,
+    /// The number of objects freed by the last major or minor collection.
+    pub freed_objects: usize,
+    /// The number of bytes freed by the last major or minor collection.
+    pub freed_bytes: usize,
+    /// The time the last collection spent tracing, summed over all of its incremental steps.
+    pub mark_duration: Duration,
+    /// The time the last collection spent deallocating, summed over all of its incremental steps.
+    pub sweep_duration: Duration,
+    /// The number of roots registered with the context.
+    pub roots: usize,
+    /// The number of objects with weak pointers to them.
+    pub weak_entries: usize,
+    /// The number of old objects that may reference young ones, and are traced by every minor
+    /// collection.
+    pub remembered_objects: usize,
+}
+
+/// The heap invariants found to be broken by `GcContext::verify_heap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcVerifyError {
+    /// A description of each problem found.
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for GcVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Heap verification failed:")?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GcVerifyError {}
+
+/// A likely pointer to another object that wasn't reported by an object's `Trace` impl, found by
+/// `GcContext::check_traces`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcUntracedEdge {
+    /// The type of the object holding the pointer.
+    pub type_name: &'static str,
+    /// The address of the object holding the pointer, as returned by `Gc::as_ptr`.
+    pub id: usize,
+    /// The offset of the pointer from the start of the object's value.
+    pub offset: usize,
+    /// The type of the object pointed to.
+    pub target_type_name: &'static str,
+    /// The address of the object pointed to, as returned by `Gc::as_ptr`.
+    pub target_id: usize,
+}
+
+impl fmt::Display for GcUntracedEdge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#x} holds a pointer to {} at {:#x} at offset {}, which it doesn't trace",
+            self.type_name, self.id, self.target_type_name, self.target_id, self.offset
+        )
+    }
+}
+
+/// Statistics gathered over the course of a single collection.
+#[derive(Clone, Copy, Default)]
+struct CycleStats {
+    freed_objects: usize,
+    freed_bytes: usize,
+    mark_duration: Duration,
+    sweep_duration: Duration,
+}
+
+/// The heap size below which no automatic collection will be started.
+const MIN_THRESHOLD: usize = 64 * 1024;
+
+/// The byte that freed objects are overwritten with by the `poison` feature.
+#[cfg(feature = "poison")]
+const POISON: u8 = 0xdd;
+
+/// The number of freed objects kept in quarantine by the `poison` feature before their slots are
+/// reused.
+#[cfg(feature = "poison")]
+const QUARANTINE_LEN: usize = 16 * 1024;
+
 pub(crate) struct GcContextData {
     roots: *mut GcRootData,
-    objects: GcDataPtr,
+    num_roots: usize,
+    heap: Heap,
+    /// Objects in the young generation.
+    nursery: Vec<GcDataPtr>,
+    nursery_config: Option<GcNursery>,
+    nursery_bytes: usize,
+    /// Old objects that may point to young objects.
+    remembered: Vec<GcDataPtr>,
+    /// Set while a minor collection is in progress.
+    minor: bool,
     weaks: Arena<GcDataPtr>,
+    /// The weak id of each object that has been downgraded.
+    weak_ids: HashMap<GcDataPtr, WeakId>,
+    /// Weak-value maps to prune whenever weak pointers are cleared.
+    weak_value_maps: Vec<rc::Weak<dyn WeakValues>>,
+    /// The ids of weak pointers cleared by the last collection, if tracking is enabled.
+    cleared_weaks: Option<Vec<GcWeakId>>,
     trace_queue: Vec<GcDataPtr>,
+    /// Set while taking a heap snapshot, to collect the objects traced instead of marking them.
+    recorded_edges: Option<Vec<RecordedEdge>>,
+    /// Whether the keys of ephemeron maps are recorded as well, since they are pointers that the
+    /// remembered set must know about even though they don't keep anything alive.
+    record_ephemeron_keys: bool,
+    /// The field that the references being recorded are stored in.
+    edge_label: Option<&'static str>,
+    /// The ids of objects that have been seen by `object_snapshot`.
+    object_ids: HashMap<GcDataPtr, u64>,
+    next_object_id: u64,
+    /// Ephemeron tables traced during the current collection.
+    ephemerons: Vec<Rc<dyn Ephemeron>>,
+    /// Objects with a finalizer that haven't been found unreachable yet.
+    finalizable: Vec<GcDataPtr>,
+    /// Unreachable objects waiting for their finalizer to run. These are treated as roots.
+    pending_finalizers: VecDeque<GcDataPtr>,
+    running_finalizers: bool,
+    cleanups: Arena<Cleanup>,
+    /// Held values and callbacks of cleanups whose targets have been collected. The held values
+    /// are treated as roots.
+    pending_cleanups: VecDeque<(GcDataPtr, CleanupFn)>,
+    phase: GcPhase,
+    /// The index of the next page to sweep.
+    sweep_cursor: usize,
+    pacing: GcPacing,
+    /// The number of bytes at which `collect_if_needed` starts a new cycle.
+    threshold: usize,
+    allocated_bytes: usize,
+    allocated_objects: usize,
+    /// Bytes and objects allocated since the last paced step.
+    debt_bytes: usize,
+    debt_objects: usize,
     num_collects: u32,
+    num_minor_collects: u32,
+    peak_bytes: usize,
+    /// Statistics for the collection in progress and the last finished collection.
+    cycle_stats: CycleStats,
+    last_cycle_stats: CycleStats,
+    observer: Option<Box<dyn GcObserver>>,
+    /// The number of allocations between forced collections, if stress collection is enabled.
+    stress_interval: Option<NonZeroU32>,
+    /// Allocations since the last forced collection.
+    stress_allocations: u32,
+    /// Whether the stack is scanned for pointers to objects, which are then treated as roots.
+    #[cfg(target_os = "linux")]
+    scan_stack: bool,
+    /// Freed objects whose slots are being kept out of use, oldest first.
+    #[cfg(feature = "poison")]
+    quarantine: VecDeque<GcDataPtr>,
 }
 
 type Error = Box<dyn std::error::Error>;
 
+/// An object referenced by a root or another object, and the field it is stored in.
+type RecordedEdge = (GcDataPtr, Option<&'static str>);
+
+/// Clears `running_finalizers` once `run_finalizers` returns or a callback panics. The entry whose
+/// callback panicked is removed from its queue, so that it isn't run again.
+struct RunningFinalizers {
+    data: *mut GcContextData,
+    /// Whether cleanup callbacks are being run, rather than finalizers.
+    cleanups: bool,
+}
+
+impl Drop for RunningFinalizers {
+    fn drop(&mut self) {
+        unsafe {
+            if std::thread::panicking() {
+                if self.cleanups {
+                    (*self.data).pending_cleanups.pop_front();
+                } else {
+                    (*self.data).pending_finalizers.pop_front();
+                }
+            }
+            (*self.data).running_finalizers = false;
+        }
+    }
+}
+
+/// A root and the objects it references, recorded while describing the heap.
+struct RecordedRoot {
+    kind: GcRootKind,
+    type_name: &'static str,
+    edges: Vec<RecordedEdge>,
+}
+
+/// Something that references an object on a retaining path.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Retainer {
+    /// An index into the recorded roots.
+    Root(usize),
+    Object(GcDataPtr),
+}
+
+/// The depth at which each object was found by `GcContext::retaining_paths`, and the retainers
+/// that reference it from the previous depth.
+type Retainers = HashMap<GcDataPtr, (usize, Vec<(Retainer, Option<&'static str>)>)>;
+
+/// Walks the retainers found by `GcContext::retaining_paths` back from `target` to the roots,
+/// returning up to `limit` paths.
+unsafe fn collect_paths(
+    target: GcDataPtr,
+    retainers: &Retainers,
+    roots: &[RecordedRoot],
+    limit: usize,
+) -> Vec<GcRetainingPath> {
+    let mut paths = Vec::new();
+    // The objects on the path being walked, starting from the target, with the index of the next
+    // retainer of each to follow. `hops` holds the hop into each of them but the last.
+    let mut stack = vec![(target, 0)];
+    let mut hops = Vec::new();
+    while let Some(&(object, next)) = stack.last() {
+        let found = &retainers[&object].1;
+        if next == found.len() {
+            stack.pop();
+            hops.pop();
+            continue;
+        }
+        if paths.len() >= limit {
+            break;
+        }
+        stack.last_mut().unwrap().1 += 1;
+        let (retainer, field) = found[next];
+        hops.push(GcPathHop {
+            field,
+            type_name: ((*object).vtbl.type_name)(),
+            id: GcData::value_ptr(object) as usize,
+        });
+        match retainer {
+            Retainer::Root(i) => {
+                paths.push(GcRetainingPath {
+                    root_kind: roots[i].kind,
+                    root_type_name: roots[i].type_name,
+                    hops: hops.iter().rev().cloned().collect(),
+                });
+                hops.pop();
+            }
+            Retainer::Object(retainer) => stack.push((retainer, 0)),
+        }
+    }
+    paths
+}
+
+/// Reads a word of an object that may be padding or otherwise uninitialized, for
+/// `GcContext::check_traces`. Reading uninitialized memory as an integer is undefined behavior in
+/// Rust, so the load is done in assembly, whose result is always initialized. Returns `None` on
+/// architectures without an implementation.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn read_word(ptr: *const MaybeUninit<usize>) -> Option<usize> {
+    let word;
+    std::arch::asm!(
+        "mov {}, [{}]",
+        out(reg) word,
+        in(reg) ptr,
+        options(nostack, preserves_flags, readonly),
+    );
+    Some(word)
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+unsafe fn read_word(ptr: *const MaybeUninit<usize>) -> Option<usize> {
+    let word;
+    std::arch::asm!(
+        "ldr {}, [{}]",
+        out(reg) word,
+        in(reg) ptr,
+        options(nostack, preserves_flags, readonly),
+    );
+    Some(word)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline(always)]
+unsafe fn read_word(_ptr: *const MaybeUninit<usize>) -> Option<usize> {
+    None
+}
+
 impl GcContext {
     pub fn new() -> Result<Self, Error> {
-        CONTEXT.with(|cell| {
-            let data = GcContextData {
-                roots: std::ptr::null_mut(),
-                objects: std::ptr::null_mut(),
-                weaks: Arena::new(),
-                trace_queue: Vec::new(),
-                num_collects: 0,
-            };
-            let ptr = Box::into_raw(Box::new(data));
-            cell.set(ptr).map_err(|_| "GcContext already created")?;
-            Ok(Self(ptr))
-        })
+        let data = GcContextData {
+            roots: std::ptr::null_mut(),
+            num_roots: 0,
+            heap: Heap::new(),
+            nursery: Vec::new(),
+            nursery_config: None,
+            nursery_bytes: 0,
+            remembered: Vec::new(),
+            minor: false,
+            weaks: Arena::new(),
+            weak_ids: HashMap::new(),
+            weak_value_maps: Vec::new(),
+            cleared_weaks: None,
+            trace_queue: Vec::new(),
+            recorded_edges: None,
+            record_ephemeron_keys: false,
+            edge_label: None,
+            object_ids: HashMap::new(),
+            next_object_id: 1,
+            ephemerons: Vec::new(),
+            finalizable: Vec::new(),
+            pending_finalizers: VecDeque::new(),
+            running_finalizers: false,
+            cleanups: Arena::new(),
+            pending_cleanups: VecDeque::new(),
+            phase: GcPhase::Idle,
+            sweep_cursor: 0,
+            pacing: GcPacing::default(),
+            threshold: MIN_THRESHOLD,
+            allocated_bytes: 0,
+            allocated_objects: 0,
+            debt_bytes: 0,
+            debt_objects: 0,
+            num_collects: 0,
+            num_minor_collects: 0,
+            peak_bytes: 0,
+            cycle_stats: CycleStats::default(),
+            last_cycle_stats: CycleStats::default(),
+            observer: None,
+            stress_interval: None,
+            stress_allocations: 0,
+            #[cfg(target_os = "linux")]
+            scan_stack: false,
+            #[cfg(feature = "poison")]
+            quarantine: VecDeque::new(),
+        };
+        Ok(Self(Box::into_raw(Box::new(data))))
     }
 
-    pub(crate) fn get() -> Self {
-        let ptr = CONTEXT.with(|cell| cell.get().unwrap().clone());
-        Self(ptr)
+    pub(crate) fn data(&self) -> *mut GcContextData {
+        self.0
     }
 
     pub fn allocate<'a, T>(&'a mut self, value: T) -> Gc<'a, T::Aged>
+    where
+        T: GcLifetime<'a> + Trace,
+    {
+        self.allocate_with_vtbl(value, T::vtbl())
+    }
+
+    /// Allocates an object whose `Finalize` implementation will be called after it becomes
+    /// unreachable.
+    ///
+    /// This is only needed for types that don't register their finalizer with `#[gc(finalize)]`.
+    pub fn allocate_with_finalizer<'a, T>(&'a mut self, value: T) -> Gc<'a, T::Aged>
+    where
+        T: GcLifetime<'a> + Finalize,
+    {
+        self.allocate_with_vtbl(value, GcVtbl::finalizable::<T>())
+    }
+
+    fn allocate_with_vtbl<'a, T>(&'a mut self, value: T, vtbl: &'static GcVtbl) -> Gc<'a, T::Aged>
+    where
+        T: GcLifetime<'a> + Trace,
+    {
+        unsafe {
+            if let Some(interval) = (*self.0).stress_interval {
+                (*self.0).stress_allocations += 1;
+                if (*self.0).stress_allocations >= interval.get() {
+                    (*self.0).stress_allocations = 0;
+                    self.collect();
+                }
+            }
+        }
+        self.allocate_without_collecting(value, vtbl)
+    }
+
+    /// Allocates an object without ever collecting, so that only shared access to the context is
+    /// needed. This is used by `GcMutation`, since nothing is rooted during a mutation session.
+    pub(crate) fn allocate_without_collecting<'a, T>(
+        &'a self,
+        value: T,
+        vtbl: &'static GcVtbl,
+    ) -> Gc<'a, T::Aged>
     where
         T: GcLifetime<'a> + Trace,
     {
         unsafe {
-            let flags = if T::needs_trace() {
+            let mut flags = if T::needs_trace() {
                 GcFlags::NEEDS_TRACE
             } else {
                 GcFlags::empty()
             };
-            let gc_box = GcData {
-                vtbl: T::vtbl(),
+            // Objects allocated mid-mark are grayed so that anything they reference is traced.
+            if (*self.0).phase == GcPhase::Marking {
+                flags |= GcFlags::GRAY;
+            }
+            let young = (*self.0).nursery_config.is_some();
+            if !young {
+                flags |= GcFlags::OLD;
+            }
+            let size = vtbl.size;
+            (*self.0).allocated_bytes += size;
+            (*self.0).allocated_objects += 1;
+            (*self.0).peak_bytes = (*self.0).peak_bytes.max((*self.0).allocated_bytes);
+            (*self.0).debt_bytes += size;
+            (*self.0).debt_objects += 1;
+            let ptr = (*self.0).heap.alloc(Layout::new::<GcData<T>>()) as *mut GcData<T>;
+            ptr.write(GcData {
+                vtbl,
                 flags,
-                weak: None,
-                next: (*self.0).objects,
                 value: UnsafeCell::new(value),
-            };
-            let ptr = Box::into_raw(Box::new(gc_box)) as *mut GcData<()>;
-            (*self.0).objects = ptr as GcDataPtr;
+            });
+            let ptr = ptr as GcDataPtr;
+            self.notify(|observer| observer.allocate(GcData::value_ptr(ptr), size));
+            if young {
+                (*self.0).nursery_bytes += size;
+                (*self.0).nursery.push(ptr);
+                Heap::set_young(ptr, true);
+            }
+            if vtbl.finalize.is_some() {
+                (*self.0).finalizable.push(ptr);
+            }
+            match (*self.0).phase {
+                GcPhase::Marking => {
+                    Heap::set_marked(ptr, true);
+                    (*self.0).trace_queue.push(ptr);
+                }
+                // Objects allocated mid-sweep must survive it. The nursery is swept last, and
+                // pages that have already been swept must be left unmarked for the next cycle.
+                GcPhase::Sweeping if young || (*Heap::page_of(ptr)).unswept => {
+                    Heap::set_marked(ptr, true);
+                }
+                _ => (),
+            }
             Gc {
                 ptr,
                 _phantom: PhantomData,
@@ -72,58 +539,1107 @@ impl GcContext {
     /// All unreachable memory will be collected and deallocated. This requires mutable access to
     /// the `GcContext`, preventing any other managed data from being accessed for the duration of
     /// the call.
+    ///
+    /// If an incremental collection cycle is already in progress, it is finished instead of
+    /// starting a new one, so objects that became unreachable after that cycle started may not be
+    /// collected until the next call. When generational collection is enabled, this is a major
+    /// collection covering both generations.
     pub fn collect(&mut self) {
+        if self.phase() == GcPhase::Idle {
+            self.collect_step(usize::MAX);
+        }
+        self.finish_cycle();
+    }
+
+    /// Performs a minor collection, deallocating unreachable objects in the nursery.
+    ///
+    /// Old objects are assumed to be alive, so only roots and the remembered set are traced. This
+    /// does nothing if generational collection is disabled. If a major cycle is in progress, it is
+    /// finished instead, since it already covers the nursery.
+    pub fn collect_minor(&mut self) {
         unsafe {
-            println!("Collect {} start:", (*self.0).num_collects);
-            // Mark
-            let mut root = (*self.0).roots;
+            let promotion_age = match (*self.0).nursery_config {
+                Some(nursery) => nursery.promotion_age.clamp(1, GcFlags::MAX_AGE),
+                None => return,
+            };
+            if (*self.0).phase != GcPhase::Idle {
+                self.finish_cycle();
+                return;
+            }
+
+            let number = (*self.0).num_minor_collects;
+            self.notify(|observer| observer.cycle_start(GcCollection::Minor, number));
+            let start = Instant::now();
+            (*self.0).minor = true;
+            self.trace_roots();
+            for i in 0..(*self.0).remembered.len() {
+                let object = (&(*self.0).remembered)[i];
+                if (*object).flags.contains(GcFlags::NEEDS_TRACE) {
+                    ((*object).vtbl.trace)(&*GcData::value_ptr(object), self);
+                }
+            }
+            self.mark(usize::MAX);
+            self.mark_ephemerons();
+            self.clear_dead_weaks();
+            self.queue_finalizers();
+            self.mark_ephemerons();
+            self.prune_ephemerons();
+            (*self.0).minor = false;
+            let mark_end = Instant::now();
+
+            self.sweep_nursery(Some(promotion_age));
+            self.rebuild_remembered();
+
+            (*self.0).cycle_stats.mark_duration = mark_end - start;
+            (*self.0).cycle_stats.sweep_duration = mark_end.elapsed();
+            self.end_cycle_stats();
+            self.notify(|observer| observer.cycle_end(GcCollection::Minor, number));
+            (*self.0).num_minor_collects += 1;
+            self.queue_cleanups();
+            self.run_finalizers();
+            #[cfg(feature = "verify")]
+            self.assert_heap_valid();
+        }
+    }
+
+    /// Returns the generational collection settings, or `None` if it is disabled.
+    pub fn nursery(&self) -> Option<GcNursery> {
+        unsafe { (*self.0).nursery_config }
+    }
+
+    /// Enables or disables generational collection.
+    ///
+    /// Any in-progress cycle is finished first. Disabling generational collection promotes every
+    /// object remaining in the nursery.
+    pub fn set_nursery(&mut self, nursery: Option<GcNursery>) {
+        unsafe {
+            self.finish_cycle();
+            if nursery.is_none() {
+                for object in (*self.0).nursery.drain(..) {
+                    (*object).flags -= GcFlags::AGE_MASK;
+                    (*object).flags |= GcFlags::OLD;
+                    Heap::set_young(object, false);
+                }
+                (*self.0).nursery_bytes = 0;
+                self.clear_remembered();
+            }
+            (*self.0).nursery_config = nursery;
+        }
+    }
+
+    /// Performs a bounded amount of incremental collection work.
+    ///
+    /// `work_budget` is the number of objects that may be traced or swept before returning. If no
+    /// cycle is in progress, a new one is started. Repeated calls will eventually finish the cycle,
+    /// at which point the context returns to `GcPhase::Idle`.
+    ///
+    /// Managed data mutated between steps must be accessed through `Gc::borrow_mut` or
+    /// `Gc::write` so that the collector can observe any newly stored pointers.
+    pub fn collect_step(&mut self, work_budget: usize) {
+        let mut budget = work_budget.max(1);
+        unsafe {
+            let start = Instant::now();
+            if (*self.0).phase == GcPhase::Idle {
+                let number = (*self.0).num_collects;
+                self.notify(|observer| observer.cycle_start(GcCollection::Major, number));
+                self.trace_roots();
+                self.set_phase(GcPhase::Marking);
+            }
+
+            if (*self.0).phase == GcPhase::Marking {
+                budget = self.mark(budget);
+                if (*self.0).trace_queue.is_empty() {
+                    self.finish_marking();
+                }
+                (*self.0).cycle_stats.mark_duration += start.elapsed();
+            }
+
+            if (*self.0).phase == GcPhase::Sweeping && budget > 0 {
+                self.sweep(budget);
+            }
+        }
+    }
+
+    /// Performs collection work if enough memory has been allocated since the last call.
+    ///
+    /// This is intended to be called regularly at points where no unrooted managed data is held,
+    /// such as once per frame or between script instructions. A new cycle is started once the heap
+    /// grows past the threshold set by `GcPacing::pause`, and each call during a cycle performs
+    /// work in proportion to the amount allocated since the previous call.
+    ///
+    /// With generational collection enabled, a minor collection is performed whenever the nursery
+    /// grows past `GcNursery::size` and no major cycle is in progress.
+    pub fn collect_if_needed(&mut self) {
+        unsafe {
+            if (*self.0).phase == GcPhase::Idle {
+                if let Some(nursery) = (*self.0).nursery_config {
+                    if (*self.0).nursery_bytes >= nursery.size {
+                        self.collect_minor();
+                    }
+                }
+            }
+
+            let data = &mut *self.0;
+            let work = if data.phase == GcPhase::Idle {
+                if data.allocated_bytes < data.threshold {
+                    return;
+                }
+                // Only start the cycle; the work is paced by allocations made from now on.
+                1
+            } else {
+                if data.debt_bytes < data.pacing.step_size {
+                    return;
+                }
+                data.debt_objects
+                    .saturating_mul(data.pacing.step_multiplier as usize)
+                    / 100
+            };
+            data.debt_bytes = 0;
+            data.debt_objects = 0;
+            self.collect_step(work);
+        }
+    }
+
+    /// Returns the parameters used by `collect_if_needed`.
+    pub fn pacing(&self) -> GcPacing {
+        unsafe { (*self.0).pacing }
+    }
+
+    /// Sets the parameters used by `collect_if_needed`.
+    pub fn set_pacing(&mut self, pacing: GcPacing) {
+        unsafe {
+            (*self.0).pacing = pacing;
+        }
+    }
+
+    /// Returns the total size in bytes of all managed data, including object headers.
+    pub fn allocated_bytes(&self) -> usize {
+        unsafe { (*self.0).allocated_bytes }
+    }
+
+    /// Returns the number of managed objects.
+    pub fn allocated_objects(&self) -> usize {
+        unsafe { (*self.0).allocated_objects }
+    }
+
+    /// Returns statistics about the heap and the last finished collection.
+    pub fn stats(&self) -> GcStats {
+        unsafe {
+            let data = &*self.0;
+            let cycle = data.last_cycle_stats;
+            GcStats {
+                collections: data.num_collects,
+                minor_collections: data.num_minor_collects,
+                live_objects: data.allocated_objects,
+                live_bytes: data.allocated_bytes,
+                peak_bytes: data.peak_bytes,
+                freed_objects: cycle.freed_objects,
+                freed_bytes: cycle.freed_bytes,
+                mark_duration: cycle.mark_duration,
+                sweep_duration: cycle.sweep_duration,
+                roots: data.num_roots,
+                weak_entries: data.weaks.len(),
+                remembered_objects: data.remembered.len(),
+            }
+        }
+    }
+
+    /// Takes a snapshot of every object in the heap and the references between them.
+    ///
+    /// Any in-progress cycle is finished first. Objects that have become unreachable since are
+    /// still included, so a full collection should be performed beforehand to only see live
+    /// objects.
+    pub fn heap_snapshot(&mut self) -> GcHeapSnapshot {
+        self.finish_cycle();
+        unsafe {
+            let ids = |edges: Vec<RecordedEdge>| {
+                edges
+                    .into_iter()
+                    .map(|(object, _)| GcData::value_ptr(object) as usize)
+                    .collect()
+            };
+            let roots = self
+                .record_roots()
+                .into_iter()
+                .map(|root| GcRootInfo {
+                    kind: root.kind,
+                    type_name: root.type_name,
+                    edges: ids(root.edges),
+                })
+                .collect();
+
+            let mut objects = Vec::with_capacity((*self.0).allocated_objects);
+            for &page in (*self.0).heap.pages() {
+                Page::for_each_object(page, |object| {
+                    let vtbl = (*object).vtbl;
+                    objects.push(GcObjectInfo {
+                        id: GcData::value_ptr(object) as usize,
+                        type_name: (vtbl.type_name)(),
+                        size: vtbl.size,
+                        edges: ids(self.record_edges(object)),
+                    });
+                });
+            }
+            GcHeapSnapshot { objects, roots }
+        }
+    }
+
+    /// Takes a snapshot of the identity, type and size of every object, to be compared against a
+    /// later snapshot with `GcObjectSnapshot::diff`.
+    ///
+    /// Unlike addresses, object ids are never reused, so an object that has been freed can't be
+    /// mistaken for a new object allocated in its place. Ids are assigned to objects when they are
+    /// first seen by a snapshot, so taking one has an ongoing cost in memory.
+    pub fn object_snapshot(&self) -> GcObjectSnapshot {
+        unsafe {
+            let data = &mut *self.0;
+            let mut objects = Vec::with_capacity(data.allocated_objects);
+            for &page in data.heap.pages() {
+                Page::for_each_object(page, |object| {
+                    let id = *data.object_ids.entry(object).or_insert_with(|| {
+                        data.next_object_id += 1;
+                        data.next_object_id - 1
+                    });
+                    let vtbl = (*object).vtbl;
+                    objects.push(GcObjectEntry {
+                        id,
+                        type_name: (vtbl.type_name)(),
+                        size: vtbl.size,
+                    });
+                });
+            }
+            objects.sort_unstable_by_key(|object| object.id);
+            GcObjectSnapshot { objects }
+        }
+    }
+
+    /// Checks the internal consistency of the heap, returning every problem found.
+    ///
+    /// This verifies that:
+    ///
+    /// - Every reference traced from the roots points to an allocated object.
+    /// - Once marking has finished, no marked object references an object that is about to be
+    ///   freed.
+    /// - Every old object referencing a young object is in the remembered set.
+    /// - The list of roots is well formed.
+    /// - Every weak pointer that hasn't been cleared points to an allocated object.
+    ///
+    /// Broken invariants are usually caused by an `unsafe impl Trace` that doesn't visit the same
+    /// pointers every time, or by managed data that is mutated without `Gc::borrow_mut` or
+    /// `Gc::write`. With the `verify` feature, this is run after every collection, including
+    /// ones finished by `collect_step`, and panics on failure.
+    pub fn verify_heap(&self) -> Result<(), GcVerifyError> {
+        unsafe {
+            let data = &*self.0;
+            let mut problems = Vec::new();
+            let pages: HashSet<*mut Page> = data.heap.pages().iter().copied().collect();
+            let is_object = |ptr: GcDataPtr| {
+                let page = Heap::page_of(ptr);
+                pages.contains(&page) && Page::is_allocated(page, ptr)
+            };
+            let describe = |ptr: GcDataPtr| {
+                format!(
+                    "{} at {:?}",
+                    ((*ptr).vtbl.type_name)(),
+                    GcData::value_ptr(ptr)
+                )
+            };
+            let field = |label: Option<&str>| label.map(|label| format!(".{}", label));
+
+            // Everything reachable only references allocated objects.
+            let young_exists = data.nursery_config.is_some();
+            let mut visited = HashSet::new();
+            let mut queue = Vec::new();
+            for root in self.record_roots() {
+                for (object, label) in root.edges {
+                    if !is_object(object) {
+                        problems.push(format!(
+                            "{:?} root {}{} references {:?}, which isn't an allocated object",
+                            root.kind,
+                            root.type_name,
+                            field(label).unwrap_or_default(),
+                            object
+                        ));
+                    } else if visited.insert(object) {
+                        queue.push(object);
+                    }
+                }
+            }
+            while let Some(object) = queue.pop() {
+                let old = (*object).flags.contains(GcFlags::OLD);
+                let remembered = (*object).flags.contains(GcFlags::REMEMBERED);
+                for (child, label) in self.record_pointers(object) {
+                    if !is_object(child) {
+                        problems.push(format!(
+                            "{}{} references {:?}, which isn't an allocated object",
+                            describe(object),
+                            field(label).unwrap_or_default(),
+                            child
+                        ));
+                        continue;
+                    }
+                    if young_exists && old && !remembered && !(*child).flags.contains(GcFlags::OLD)
+                    {
+                        problems.push(format!(
+                            "{}{} references young {}, but isn't in the remembered set",
+                            describe(object),
+                            field(label).unwrap_or_default(),
+                            describe(child)
+                        ));
+                    }
+                    if visited.insert(child) {
+                        queue.push(child);
+                    }
+                }
+            }
+
+            // Marked objects only reference objects that will survive the sweep. Objects on
+            // pages that have already been swept have been unmarked, unless they are young.
+            if data.phase == GcPhase::Sweeping {
+                for &page in data.heap.pages() {
+                    if !(*page).unswept {
+                        continue;
+                    }
+                    Page::for_each_object(page, |object| {
+                        if !Heap::is_marked(object) {
+                            return;
+                        }
+                        for (child, label) in self.record_pointers(object) {
+                            // Dangling children are reported by the checks above, and may be on
+                            // pages that have already been released.
+                            if !is_object(child) {
+                                continue;
+                            }
+                            let doomed = (*Heap::page_of(child)).unswept
+                                || !(*child).flags.contains(GcFlags::OLD);
+                            if doomed && !Heap::is_marked(child) {
+                                problems.push(format!(
+                                    "Marked {}{} references unmarked {}",
+                                    describe(object),
+                                    field(label).unwrap_or_default(),
+                                    describe(child)
+                                ));
+                            }
+                        }
+                    });
+                }
+            }
+
+            let mut prev = std::ptr::null_mut();
+            let mut root = data.roots;
+            let mut num_roots = 0;
             while !root.is_null() {
-                ((*root).vtbl.trace)(&*(*root).value, self);
+                if (*root).prev != prev {
+                    problems.push(format!(
+                        "Root {:?} links back to {:?} instead of {:?}",
+                        root,
+                        (*root).prev,
+                        prev
+                    ));
+                }
+                if (*root).ctx != self.0 {
+                    problems.push(format!("Root {:?} belongs to another context", root));
+                }
+                prev = root;
                 root = (*root).next;
+                num_roots += 1;
+            }
+            if num_roots != data.num_roots {
+                problems.push(format!(
+                    "Found {} roots, but {} are registered",
+                    num_roots, data.num_roots
+                ));
             }
 
-            while let Some(object) = (*self.0).trace_queue.pop() {
-                (*object).flags -= GcFlags::COLOR_MASK;
-                (*object).flags |= GcFlags::BLACK;
-                if (*object).flags.contains(GcFlags::NEEDS_TRACE) {
-                    ((*object).vtbl.trace)(&*(*object).value.get(), self);
+            for (id, &object) in data.weaks.iter() {
+                if !is_object(object) {
+                    problems.push(format!(
+                        "Weak pointer to {:?} wasn't cleared when it was freed",
+                        object
+                    ));
+                } else if data.weak_ids.get(&object).map(|id| id.index) != Some(id)
+                    || !(*object).flags.contains(GcFlags::HAS_WEAK)
+                {
+                    problems.push(format!(
+                        "Weak id of {} doesn't match its weak pointer",
+                        describe(object)
+                    ));
                 }
             }
 
-            // Sweep
-            let mut prev: GcDataPtr = std::ptr::null_mut();
-            let mut object = (*self.0).objects;
-            while !object.is_null() {
-                let free = if ((*object).flags & GcFlags::COLOR_MASK) != GcFlags::WHITE {
-                    (*object).flags -= GcFlags::COLOR_MASK;
-                    (*object).flags |= GcFlags::WHITE;
-                    false
-                } else {
-                    if !prev.is_null() {
-                        (*prev).next = (*object).next;
-                    } else {
-                        (*self.0).objects = (*object).next;
+            if problems.is_empty() {
+                Ok(())
+            } else {
+                Err(GcVerifyError { problems })
+            }
+        }
+    }
+
+    /// Looks for pointers to managed objects that an object's `Trace` impl doesn't report.
+    ///
+    /// The value of every object is scanned for words that equal the address of an allocated
+    /// object, and each one that isn't among the references traced from the object is returned.
+    /// This is meant for checking hand-written `unsafe impl Trace`s, where forgetting a field
+    /// leads to a use after free.
+    ///
+    /// Matches are heuristic. Pointers stored outside of the value itself, such as in a `Vec`,
+    /// aren't found. Any word that happens to equal an address is reported as a pointer, whether
+    /// it is an integer, padding, or uninitialized memory such as a `MaybeUninit` field or an
+    /// unused enum payload. Nothing is scanned on architectures other than x86-64 and AArch64.
+    pub fn check_traces(&self) -> Vec<GcUntracedEdge> {
+        unsafe {
+            let data = &*self.0;
+            let pages: HashSet<*mut Page> = data.heap.pages().iter().copied().collect();
+            let is_object = |ptr: GcDataPtr| {
+                let page = Heap::page_of(ptr);
+                pages.contains(&page) && Page::is_allocated(page, ptr)
+            };
+
+            let mut untraced = Vec::new();
+            for &page in data.heap.pages() {
+                Page::for_each_object(page, |object| {
+                    let vtbl = (*object).vtbl;
+                    let value = GcData::value_ptr(object) as usize;
+                    let end = object as usize + vtbl.size;
+                    let word = std::mem::size_of::<usize>();
+                    let mut traced = None;
+                    let mut addr = value.next_multiple_of(word);
+                    while addr + word <= end {
+                        let target = read_word(addr as *const MaybeUninit<usize>)
+                            .map(|word| word as GcDataPtr)
+                            .filter(|&target| is_object(target));
+                        if let Some(target) = target {
+                            let traced = traced.get_or_insert_with(|| {
+                                self.record_edges(object)
+                                    .into_iter()
+                                    .map(|(child, _)| child)
+                                    .collect::<HashSet<_>>()
+                            });
+                            if !traced.contains(&target) {
+                                untraced.push(GcUntracedEdge {
+                                    type_name: (vtbl.type_name)(),
+                                    id: value,
+                                    offset: addr - value,
+                                    target_type_name: ((*target).vtbl.type_name)(),
+                                    target_id: GcData::value_ptr(target) as usize,
+                                });
+                            }
+                        }
+                        addr += word;
                     }
-                    true
-                };
-                let next = (*object).next;
-                if free {
-                    println!("Free {:?}", object);
-                    if let Some(id) = (*object).weak {
-                        (*self.0).weaks.remove(id);
+                });
+            }
+            untraced
+        }
+    }
+
+    /// Panics if `verify_heap` finds any problems.
+    #[cfg(feature = "verify")]
+    fn assert_heap_valid(&self) {
+        if let Err(error) = self.verify_heap() {
+            panic!("{}", error);
+        }
+    }
+
+    /// Finds the shortest chains of references from a root to `object`, explaining why it is
+    /// still alive.
+    ///
+    /// At most `limit` paths are returned, all of the same length. Each hop is labelled with the
+    /// field it was found in when the referencing type derives `Gc`. The result is empty if the
+    /// object is unreachable, for example if it has just been allocated and not yet stored
+    /// anywhere.
+    pub fn retaining_paths<T>(&self, object: Gc<'_, T>, limit: usize) -> Vec<GcRetainingPath> {
+        unsafe {
+            let target = object.ptr as GcDataPtr;
+            let roots = self.record_roots();
+
+            // A breadth-first search from the roots, recording every retainer of each object that
+            // lies on a shortest path to it.
+            let mut retainers = Retainers::new();
+            let mut queue = VecDeque::new();
+            let mut visit =
+                |object, depth, retainer, label, queue: &mut VecDeque<_>| match retainers
+                    .entry(object)
+                {
+                    Entry::Vacant(entry) => {
+                        entry.insert((depth, vec![(retainer, label)]));
+                        queue.push_back((object, depth));
                     }
-                    ((*object).vtbl.dealloc)(object as *mut GcData<()> as *mut ());
-                } else {
-                    prev = object;
+                    Entry::Occupied(mut entry) => {
+                        let (found_depth, found) = entry.get_mut();
+                        if *found_depth == depth && !found.contains(&(retainer, label)) {
+                            found.push((retainer, label));
+                        }
+                    }
+                };
+            for (i, root) in roots.iter().enumerate() {
+                for &(object, label) in &root.edges {
+                    visit(object, 0, Retainer::Root(i), label, &mut queue);
+                }
+            }
+            let mut target_depth = usize::MAX;
+            while let Some((object, depth)) = queue.pop_front() {
+                if object == target {
+                    target_depth = depth;
+                }
+                if depth >= target_depth {
+                    break;
+                }
+                for (child, label) in self.record_edges(object) {
+                    visit(
+                        child,
+                        depth + 1,
+                        Retainer::Object(object),
+                        label,
+                        &mut queue,
+                    );
+                }
+            }
+
+            if target_depth == usize::MAX {
+                return Vec::new();
+            }
+            collect_paths(target, &retainers, &roots, limit)
+        }
+    }
+
+    /// Traces every root, returning the objects each of them references.
+    unsafe fn record_roots(&self) -> Vec<RecordedRoot> {
+        let mut roots = Vec::new();
+        let mut root = (*self.0).roots;
+        while !root.is_null() {
+            let vtbl = (*root).vtbl;
+            roots.push(RecordedRoot {
+                kind: (*root).kind,
+                type_name: (vtbl.type_name)(),
+                edges: self.record_value_edges((*root).value, vtbl, false),
+            });
+            root = (*root).next;
+        }
+
+        let data = &*self.0;
+        let pending_finalizers = data.pending_finalizers.iter().copied();
+        let held = data.cleanups.iter().map(|(_, cleanup)| cleanup.held);
+        let pending_held = data.pending_cleanups.iter().map(|&(held, _)| held);
+        let internal_roots = pending_finalizers
+            .map(|object| (GcRootKind::Finalizer, object))
+            .chain(
+                held.chain(pending_held)
+                    .map(|object| (GcRootKind::Cleanup, object)),
+            );
+        for (kind, object) in internal_roots {
+            roots.push(RecordedRoot {
+                kind,
+                type_name: ((*object).vtbl.type_name)(),
+                edges: vec![(object, None)],
+            });
+        }
+        roots
+    }
+
+    /// Traces an object, returning the objects it references.
+    unsafe fn record_edges(&self, object: GcDataPtr) -> Vec<RecordedEdge> {
+        self.record_object_edges(object, false)
+    }
+
+    /// Traces an object, returning every object it points to, including the keys of ephemeron
+    /// maps.
+    unsafe fn record_pointers(&self, object: GcDataPtr) -> Vec<RecordedEdge> {
+        self.record_object_edges(object, true)
+    }
+
+    unsafe fn record_object_edges(&self, object: GcDataPtr, keys: bool) -> Vec<RecordedEdge> {
+        if !(*object).flags.contains(GcFlags::NEEDS_TRACE) {
+            return Vec::new();
+        }
+        self.record_value_edges(GcData::value_ptr(object), (*object).vtbl, keys)
+    }
+
+    unsafe fn record_value_edges(
+        &self,
+        value: *const (),
+        vtbl: &GcVtbl,
+        keys: bool,
+    ) -> Vec<RecordedEdge> {
+        // Recording only appends to `recorded_edges`, so it doesn't need exclusive access to the
+        // context, and can describe the heap while managed data is borrowed.
+        let mut ctx = GcContext(self.0);
+        (*self.0).recorded_edges = Some(Vec::new());
+        (*self.0).record_ephemeron_keys = keys;
+        (*self.0).edge_label = None;
+        (vtbl.trace_fields)(&*value, &mut ctx);
+        (*self.0).record_ephemeron_keys = false;
+        (*self.0).recorded_edges.take().unwrap_or_default()
+    }
+
+    /// Labels the references traced from now on with the name of the field they are stored in.
+    ///
+    /// This is called by `Trace::trace_fields` implementations generated by `#[derive(Gc)]`.
+    #[doc(hidden)]
+    pub fn set_edge_label(&mut self, label: &'static str) {
+        unsafe {
+            (*self.0).edge_label = Some(label);
+        }
+    }
+
+    /// Returns whether a heap snapshot is being taken, rather than a collection performed.
+    pub(crate) fn is_recording(&self) -> bool {
+        unsafe { (*self.0).recorded_edges.is_some() }
+    }
+
+    /// Returns whether the keys of ephemeron maps should be recorded along with their values.
+    pub(crate) fn is_recording_keys(&self) -> bool {
+        unsafe { (*self.0).record_ephemeron_keys }
+    }
+
+    /// Returns the phase of the current collection cycle.
+    pub fn phase(&self) -> GcPhase {
+        unsafe { (*self.0).phase }
+    }
+
+    /// Enables or disables recording the weak pointers cleared by each collection.
+    pub fn set_track_cleared_weaks(&mut self, enabled: bool) {
+        unsafe {
+            (*self.0).cleared_weaks = enabled.then(Vec::new);
+        }
+    }
+
+    /// Returns the ids of the weak pointers cleared by the last collection, so that bookkeeping
+    /// associated with them can be dropped eagerly.
+    ///
+    /// This is always empty unless tracking has been enabled with `set_track_cleared_weaks`. The
+    /// list is replaced once the marking phase of each major or minor collection finishes.
+    pub fn cleared_weaks(&self) -> &[GcWeakId] {
+        unsafe { (*self.0).cleared_weaks.as_deref().unwrap_or_default() }
+    }
+
+    /// Makes every `interval`th allocation perform a full collection before allocating, or stops
+    /// doing so if `interval` is `None`.
+    ///
+    /// This is very slow, but data that should have been rooted is then freed by the next
+    /// allocation rather than whenever a collection happens to run, so missing roots fail
+    /// deterministically. An interval of 1 collects on every allocation.
+    pub fn set_stress_interval(&mut self, interval: Option<NonZeroU32>) {
+        unsafe {
+            (*self.0).stress_interval = interval;
+            (*self.0).stress_allocations = 0;
+        }
+    }
+
+    /// Returns the number of allocations between forced collections, or `None` if stress
+    /// collection is disabled.
+    pub fn stress_interval(&self) -> Option<NonZeroU32> {
+        unsafe { (*self.0).stress_interval }
+    }
+
+    /// Enables or disables conservative scanning of the current thread's stack.
+    ///
+    /// When enabled, every collection treats any object that a word on the stack points into as a
+    /// root, so managed data held in locals is kept alive without `pin_root!`. Such locals can be
+    /// detached from the borrow of the context with `GcLifetime::change_lifetime`. Objects are
+    /// never moved, so this is only imprecise in that an integer which happens to look like a
+    /// pointer keeps an object alive.
+    ///
+    /// # Safety
+    ///
+    /// Only the stack of the thread that collects is scanned. Any unrooted `Gc` that the caller
+    /// relies on being kept alive by the scan must be held in a local on that stack, or in a
+    /// register, for as long as it is used. One held in a heap allocation such as a `Box` or
+    /// `Vec`, or on another thread, isn't a root and will be collected. It must also not be
+    /// used after scanning has been disabled.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn set_scan_stack(&mut self, enabled: bool) {
+        (*self.0).scan_stack = enabled;
+    }
+
+    /// Returns whether conservative stack scanning is enabled.
+    #[cfg(target_os = "linux")]
+    pub fn scan_stack(&self) -> bool {
+        unsafe { (*self.0).scan_stack }
+    }
+
+    /// Installs hooks that are called as objects are allocated and collected, returning the
+    /// previously installed observer. Collection is silent when no observer is installed.
+    pub fn set_observer(
+        &mut self,
+        observer: Option<Box<dyn GcObserver>>,
+    ) -> Option<Box<dyn GcObserver>> {
+        unsafe { std::mem::replace(&mut (*self.0).observer, observer) }
+    }
+
+    /// Runs the in-progress collection cycle to completion, if there is one.
+    fn finish_cycle(&mut self) {
+        while self.phase() != GcPhase::Idle {
+            self.collect_step(usize::MAX);
+        }
+    }
+
+    /// Traces every root, graying any data they reference.
+    unsafe fn trace_roots(&mut self) {
+        let mut root = (*self.0).roots;
+        while !root.is_null() {
+            ((*root).vtbl.trace)(&*(*root).value, self);
+            root = (*root).next;
+        }
+
+        for i in 0..(*self.0).pending_finalizers.len() {
+            self.trace((&(*self.0).pending_finalizers)[i]);
+        }
+        #[cfg(target_os = "linux")]
+        if (*self.0).scan_stack {
+            self.trace_stack();
+        }
+        for (_, cleanup) in (*self.0).cleanups.iter() {
+            self.trace(cleanup.held);
+        }
+        for i in 0..(*self.0).pending_cleanups.len() {
+            self.trace((&(*self.0).pending_cleanups)[i].0);
+        }
+    }
+
+    /// Traces every object that a word on the stack points into.
+    #[cfg(target_os = "linux")]
+    unsafe fn trace_stack(&mut self) {
+        let pages: HashSet<*mut Page> = (*self.0).heap.pages().iter().copied().collect();
+        crate::stack::scan_stack(|word| {
+            let page = Heap::page_of(word as GcDataPtr);
+            if pages.contains(&page) {
+                if let Some(object) = Page::object_containing(page, word) {
+                    self.trace(object);
                 }
-                object = next;
             }
+        });
+    }
+
+    /// Traces gray objects until the queue is empty or the budget runs out, returning the budget
+    /// that remains.
+    unsafe fn mark(&mut self, mut budget: usize) -> usize {
+        while budget > 0 {
+            let object = match (*self.0).trace_queue.pop() {
+                Some(object) => object,
+                None => break,
+            };
+            (*object).flags -= GcFlags::GRAY;
+            if (*object).flags.contains(GcFlags::NEEDS_TRACE) {
+                ((*object).vtbl.trace)(&*GcData::value_ptr(object), self);
+            }
+            budget -= 1;
+        }
+        budget
+    }
+
+    /// Atomically completes the mark phase and prepares for sweeping.
+    ///
+    /// Roots may have been added or changed since the cycle started, so they are traced again and
+    /// the queue is drained without a budget. Weak pointers to unmarked objects are then cleared so
+    /// that they can't be upgraded while the sweep is in progress, and unmarked objects with
+    /// finalizers are revived until their finalizers have run. Finally, ephemeron entries with
+    /// unmarked keys are removed.
+    unsafe fn finish_marking(&mut self) {
+        self.trace_roots();
+        self.mark(usize::MAX);
+        self.mark_ephemerons();
+        self.clear_dead_weaks();
+        self.queue_finalizers();
+        self.mark_ephemerons();
+        self.prune_ephemerons();
+        // Unmarked objects are about to be freed, so they are dropped from the remembered set in
+        // one pass rather than as each is swept.
+        (*self.0)
+            .remembered
+            .retain(|&object| Heap::is_marked(object));
+
+        self.set_phase(GcPhase::Sweeping);
+        (*self.0).sweep_cursor = 0;
+        (*self.0).heap.start_sweep();
+        #[cfg(feature = "verify")]
+        self.assert_heap_valid();
+    }
+
+    /// Sweeps pages until roughly `budget` objects have been visited, deallocating any old
+    /// objects that weren't marked. Once every page has been swept, the nursery is swept and the
+    /// cycle is finished.
+    unsafe fn sweep(&mut self, mut budget: usize) {
+        let start = Instant::now();
+        while budget > 0 {
+            let page = match (*self.0).heap.pages().get((*self.0).sweep_cursor) {
+                Some(&page) => page,
+                None => break,
+            };
+            (*self.0).sweep_cursor += 1;
+            // Pages allocated during the sweep have nothing to free.
+            if !(*page).unswept {
+                continue;
+            }
+            budget = budget.saturating_sub(Page::live(page).max(1));
+            Page::sweep(page, |object| self.free_object(object));
+        }
+
+        if (*self.0).sweep_cursor >= (*self.0).heap.pages().len() {
+            self.sweep_nursery(None);
+            (*self.0).heap.release_empty_pages();
+            (*self.0).cycle_stats.sweep_duration += start.elapsed();
+            self.end_cycle_stats();
 
-            println!("Collect {} end\n", (*self.0).num_collects);
+            let number = (*self.0).num_collects;
             (*self.0).num_collects += 1;
+            self.set_phase(GcPhase::Idle);
+            self.notify(|observer| observer.cycle_end(GcCollection::Major, number));
+            (*self.0).threshold = ((*self.0).allocated_bytes / 100)
+                .saturating_mul((*self.0).pacing.pause as usize)
+                .max(MIN_THRESHOLD);
+            self.queue_cleanups();
+            self.run_finalizers();
+            #[cfg(feature = "verify")]
+            self.assert_heap_valid();
+        } else {
+            (*self.0).cycle_stats.sweep_duration += start.elapsed();
+        }
+    }
+
+    /// Publishes the statistics of the collection that just finished, and resets them for the next.
+    unsafe fn end_cycle_stats(&mut self) {
+        (*self.0).last_cycle_stats = std::mem::take(&mut (*self.0).cycle_stats);
+    }
+
+    /// Traces ephemeron values whose keys have been marked until no more objects are marked.
+    ///
+    /// Tracing a value may mark the key of another entry, or trace a new table, so this repeats
+    /// until a pass over every table finds nothing new.
+    unsafe fn mark_ephemerons(&mut self) {
+        loop {
+            let mut i = 0;
+            while i < (*self.0).ephemerons.len() {
+                let table = (&(*self.0).ephemerons)[i].clone();
+                table.trace_values(self);
+                i += 1;
+            }
+            if (*self.0).trace_queue.is_empty() {
+                break;
+            }
+            self.mark(usize::MAX);
+        }
+    }
+
+    /// Removes ephemeron entries whose keys weren't marked, before the keys are deallocated.
+    unsafe fn prune_ephemerons(&mut self) {
+        for table in std::mem::take(&mut (*self.0).ephemerons) {
+            table.prune(self);
+        }
+    }
+
+    /// Clears weak pointers to objects that weren't marked, and removes them from weak-value maps.
+    unsafe fn clear_dead_weaks(&mut self) {
+        let minor = (*self.0).minor;
+        let weak_ids = &mut (*self.0).weak_ids;
+        let mut cleared = (*self.0).cleared_weaks.as_mut();
+        if let Some(cleared) = &mut cleared {
+            cleared.clear();
+        }
+        (*self.0).weaks.retain(|_, &mut ptr| {
+            let alive = Heap::is_marked(ptr) || (minor && (*ptr).flags.contains(GcFlags::OLD));
+            if !alive {
+                (*ptr).flags -= GcFlags::HAS_WEAK;
+                let id = weak_ids.remove(&ptr).unwrap();
+                if let Some(cleared) = &mut cleared {
+                    cleared.push(GcWeakId(id));
+                }
+            }
+            alive
+        });
+
+        // Maps that have been dropped are unregistered.
+        let weaks = &(*self.0).weaks;
+        (*self.0)
+            .weak_value_maps
+            .retain(|table| match table.upgrade() {
+                Some(table) => {
+                    table.prune(weaks);
+                    true
+                }
+                None => false,
+            });
+    }
+
+    /// Moves unmarked objects with finalizers to the pending queue, then marks them and everything
+    /// they reference so that they survive until their finalizers have run.
+    unsafe fn queue_finalizers(&mut self) {
+        let minor = (*self.0).minor;
+        let mut i = 0;
+        while i < (*self.0).finalizable.len() {
+            let object = (&(*self.0).finalizable)[i];
+            if Heap::is_marked(object) || (minor && (*object).flags.contains(GcFlags::OLD)) {
+                i += 1;
+                continue;
+            }
+            (*self.0).finalizable.swap_remove(i);
+            (*self.0).pending_finalizers.push_back(object);
+            self.trace(object);
+        }
+        self.mark(usize::MAX);
+    }
+
+    /// Moves cleanups whose targets have been collected to the pending queue.
+    unsafe fn queue_cleanups(&mut self) {
+        let weaks = &(*self.0).weaks;
+        let pending = &mut (*self.0).pending_cleanups;
+        (*self.0).cleanups.retain(|_, cleanup| {
+            if weaks.contains(cleanup.target.index) {
+                return true;
+            }
+            pending.push_back((cleanup.held, cleanup.callback));
+            false
+        });
+    }
+
+    /// Runs the finalizers of objects found to be unreachable by the last collection, followed by
+    /// the callbacks of cleanups whose targets were collected.
+    ///
+    /// These may allocate or collect; a collection started by a callback leaves any new callbacks
+    /// for the outer call to run.
+    unsafe fn run_finalizers(&mut self) {
+        if (*self.0).running_finalizers {
+            return;
+        }
+        (*self.0).running_finalizers = true;
+        let mut guard = RunningFinalizers {
+            data: self.0,
+            cleanups: false,
+        };
+        // Objects stay in their queue, and therefore rooted, while their callback runs.
+        while let Some(&object) = (*self.0).pending_finalizers.front() {
+            ((*object).vtbl.finalize.unwrap())(object, self);
+            (*self.0).pending_finalizers.pop_front();
+        }
+        guard.cleanups = true;
+        while let Some(&(held, callback)) = (*self.0).pending_cleanups.front() {
+            let held = Gc {
+                ptr: held,
+                _phantom: PhantomData,
+            };
+            callback(held, self);
+            (*self.0).pending_cleanups.pop_front();
+        }
+    }
+
+    /// Deallocates unmarked objects in the nursery and unmarks the rest.
+    ///
+    /// After a minor collection, survivors are aged and promoted once they reach `promotion_age`.
+    unsafe fn sweep_nursery(&mut self, promotion_age: Option<u8>) {
+        let mut i = 0;
+        while i < (*self.0).nursery.len() {
+            let object = (&(*self.0).nursery)[i];
+            if !Heap::is_marked(object) {
+                (*self.0).nursery.swap_remove(i);
+                self.free_object(object);
+                continue;
+            }
+            Heap::set_marked(object, false);
+
+            let promotion_age = match promotion_age {
+                Some(promotion_age) => promotion_age,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let flags = &mut (*object).flags;
+            if flags.age() + 1 >= promotion_age {
+                // Promoted objects may still point to objects left in the nursery.
+                *flags -= GcFlags::AGE_MASK;
+                *flags |= GcFlags::OLD | GcFlags::REMEMBERED;
+                Heap::set_young(object, false);
+                (*self.0).nursery.swap_remove(i);
+                (*self.0).nursery_bytes -= (*object).vtbl.size;
+                (*self.0).remembered.push(object);
+            } else {
+                flags.set_age(flags.age() + 1);
+                i += 1;
+            }
         }
     }
 
+    /// Keeps only the objects in the remembered set that still reference young objects, once a
+    /// minor collection has promoted or freed the rest of the nursery.
+    unsafe fn rebuild_remembered(&mut self) {
+        if (*self.0).nursery.is_empty() {
+            self.clear_remembered();
+            return;
+        }
+        for object in std::mem::take(&mut (*self.0).remembered) {
+            let references_young = self
+                .record_pointers(object)
+                .iter()
+                .any(|&(child, _)| !(*child).flags.contains(GcFlags::OLD));
+            if references_young {
+                (*self.0).remembered.push(object);
+            } else {
+                (*object).flags -= GcFlags::REMEMBERED;
+            }
+        }
+    }
+
+    unsafe fn set_phase(&mut self, phase: GcPhase) {
+        (*self.0).phase = phase;
+        self.notify(|observer| observer.phase_change(phase));
+    }
+
+    /// Calls `f` with the installed observer, if there is one.
+    #[inline]
+    unsafe fn notify(&self, f: impl FnOnce(&mut dyn GcObserver)) {
+        if let Some(observer) = &mut (*self.0).observer {
+            f(observer.as_mut());
+        }
+    }
+
+    /// Drops an object and returns its slot to the heap.
+    unsafe fn free_object(&mut self, object: GcDataPtr) {
+        let size = (*object).vtbl.size;
+        self.notify(|observer| observer.free(GcData::value_ptr(object), size));
+        (*self.0).cycle_stats.freed_objects += 1;
+        (*self.0).cycle_stats.freed_bytes += size;
+        if !(*self.0).object_ids.is_empty() {
+            (*self.0).object_ids.remove(&object);
+        }
+        (*self.0).allocated_bytes -= size;
+        (*self.0).allocated_objects -= 1;
+        if !(*object).flags.contains(GcFlags::OLD) {
+            (*self.0).nursery_bytes -= size;
+        }
+        ((*object).vtbl.drop)(object as *mut ());
+        #[cfg(feature = "poison")]
+        self.quarantine(object);
+        #[cfg(not(feature = "poison"))]
+        (*self.0).heap.free(object);
+    }
+
+    /// Poisons a dropped object and keeps its slot out of use for a while, so that any later use
+    /// of it panics instead of reading whatever reuses the slot.
+    #[cfg(feature = "poison")]
+    unsafe fn quarantine(&mut self, object: GcDataPtr) {
+        let vtbl = (*object).vtbl;
+        std::ptr::write_bytes(
+            (object as *mut u8).add(vtbl.value_offset),
+            POISON,
+            vtbl.size - vtbl.value_offset,
+        );
+        (*object).flags = GcFlags::DEAD;
+        Heap::retire(object);
+        (*self.0).quarantine.push_back(object);
+        if (*self.0).quarantine.len() > QUARANTINE_LEN {
+            let object = (*self.0).quarantine.pop_front().unwrap();
+            (*self.0).heap.reuse(object);
+        }
+    }
+
+    /// Empties the remembered set once no young objects remain for it to point to.
+    unsafe fn clear_remembered(&mut self) {
+        for &object in &(*self.0).remembered {
+            (*object).flags -= GcFlags::REMEMBERED;
+        }
+        (*self.0).remembered.clear();
+    }
+
     /// Consume the context, deallocating all managed data. All roots should be dropped before
     /// calling this method.
     ///
@@ -138,64 +1654,201 @@ impl GcContext {
             }
 
             // Deallocate all remaining managed data.
-            let mut object = (*self.0).objects;
-            while !object.is_null() {
-                ((*object).vtbl.dealloc)(object as *mut GcData<()> as *mut ());
-                object = (*object).next;
+            for &page in (*self.0).heap.pages() {
+                Page::for_each_object(page, |object| ((*object).vtbl.drop)(object as *mut ()));
             }
+            (*self.0).heap.release_all_pages();
 
             // Deallocate myself.
-            CONTEXT.with(|cell| {
-                let _ = Box::from_raw(*cell.get().unwrap());
-                // Box dropped here
-            })
+            drop(Box::from_raw(self.0));
         }
     }
 
+    #[track_caller]
     pub(crate) fn get_weak<'a, T>(&'a self, weak: GcWeak<'a, T>) -> Option<Gc<'a, T>> {
+        assert!(
+            weak.id.ctx == self.0,
+            "A weak pointer was used with a context other than the one that created it"
+        );
         unsafe {
-            (*self.0).weaks.get(weak.id).map(|&ptr| Gc {
+            (*self.0).weaks.get(weak.id.index).map(|&ptr| Gc {
                 ptr,
                 _phantom: Default::default(),
             })
         }
     }
 
-    pub(crate) fn add_weak<'a, T>(&'a self, ptr: *mut GcData<T>) -> WeakId {
+    /// Returns the weak id of an object, creating one if it doesn't have one yet.
+    #[track_caller]
+    pub(crate) fn weak_id<T>(&self, ptr: *mut GcData<T>) -> WeakId {
         unsafe {
-            let id = (*self.0).weaks.insert(ptr as *mut GcData<()>);
-            (*ptr).weak = Some(id);
+            let ptr = ptr as GcDataPtr;
+            self.assert_owns(ptr);
+            if (*ptr).flags.contains(GcFlags::HAS_WEAK) {
+                return (&(*self.0).weak_ids)[&ptr];
+            }
+            let id = WeakId {
+                ctx: self.0,
+                index: (*self.0).weaks.insert(ptr),
+            };
+            (*self.0).weak_ids.insert(ptr, id);
+            (*ptr).flags |= GcFlags::HAS_WEAK;
             id
         }
     }
 
-    pub(crate) unsafe fn insert_root(&mut self, root: *mut GcRootData) {
-        if !(*self.0).roots.is_null() {
-            (*(*self.0).roots).prev = root;
+    /// Returns whether an object has been marked by the current collection. Old objects are always
+    /// considered marked during a minor collection.
+    pub(crate) fn is_live(&self, ptr: GcDataPtr) -> bool {
+        unsafe { Heap::is_marked(ptr) || ((*self.0).minor && (*ptr).flags.contains(GcFlags::OLD)) }
+    }
+
+    pub(crate) fn register_weak_values(&self, table: rc::Weak<dyn WeakValues>) {
+        unsafe {
+            (*self.0).weak_value_maps.push(table);
         }
-        (*root).next = (*self.0).roots;
-        (*self.0).roots = root;
     }
 
-    pub(crate) unsafe fn remove_root(&mut self, root: *const GcRootData) {
-        if !(*root).next.is_null() {
-            (*(*root).next).prev = (*root).prev;
+    pub(crate) fn register_ephemeron(&mut self, table: Rc<dyn Ephemeron>) {
+        unsafe {
+            (*self.0).ephemerons.push(table);
         }
-        if !(*root).prev.is_null() {
-            (*(*root).prev).next = (*root).next;
-        } else {
-            (*self.0).roots = (*root).next;
+    }
+
+    /// Registers a callback to be called with `held` after `target` has been collected.
+    ///
+    /// `target` is only referenced weakly, while `held` is kept alive until the callback has run,
+    /// so `held` must not reference `target`. Like finalizers, callbacks run after the sweep with
+    /// full access to the context. The returned token can be passed to `unregister_cleanup` to
+    /// cancel the callback.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `target` or `held` was allocated by another context.
+    #[track_caller]
+    pub fn register_cleanup<T, H>(
+        &self,
+        target: Gc<'_, T>,
+        held: Gc<'_, H>,
+        callback: fn(Gc<'_, H>, &mut GcContext),
+    ) -> GcCleanupToken {
+        unsafe {
+            self.assert_owns(held.ptr);
+            let cleanup = Cleanup {
+                target: self.weak_id(target.ptr),
+                held: held.ptr,
+                callback: std::mem::transmute::<fn(Gc<'_, H>, &mut GcContext), CleanupFn>(callback),
+            };
+            GcCleanupToken((*self.0).cleanups.insert(cleanup))
+        }
+    }
+
+    /// Cancels a callback registered with `register_cleanup`, allowing its held value to be
+    /// collected. Returns `false` if the callback has already run or been cancelled, or its target
+    /// has already been collected.
+    pub fn unregister_cleanup(&self, token: GcCleanupToken) -> bool {
+        unsafe { (*self.0).cleanups.remove(token.0).is_some() }
+    }
+
+    /// Records that an object is about to be mutated.
+    ///
+    /// While marking is in progress, a black object is re-grayed. Otherwise, storing a pointer to a
+    /// white object into an already traced object would hide it from the collector. With
+    /// generational collection enabled, old objects are also added to the remembered set, since
+    /// they may now point to young objects.
+    #[inline]
+    #[track_caller]
+    pub(crate) unsafe fn write_barrier<T>(&self, ptr: *mut GcData<T>) {
+        self.assert_owns(ptr as GcDataPtr);
+        let data = &mut *ptr;
+        if (*self.0).phase == GcPhase::Marking
+            && !data.flags.contains(GcFlags::GRAY)
+            && Heap::is_marked(ptr as GcDataPtr)
+        {
+            data.flags |= GcFlags::GRAY;
+            (*self.0).trace_queue.push(ptr as *mut GcData<()>);
+        }
+        if (*self.0).nursery_config.is_some()
+            && data.flags.contains(GcFlags::OLD)
+            && !data.flags.contains(GcFlags::REMEMBERED)
+        {
+            data.flags |= GcFlags::REMEMBERED;
+            (*self.0).remembered.push(ptr as *mut GcData<()>);
         }
     }
 
     #[inline]
-    pub(crate) unsafe fn trace<'a, T>(&mut self, ptr: *mut GcData<T>) {
+    pub(crate) unsafe fn trace<T>(&mut self, ptr: *mut GcData<T>) {
+        // Freed objects are reported by `verify_heap` rather than panicking while recording.
+        if let Some(edges) = &mut (*self.0).recorded_edges {
+            edges.push((ptr as GcDataPtr, (*self.0).edge_label));
+            return;
+        }
+        GcData::assert_live(ptr as GcDataPtr);
+        self.assert_owns(ptr as GcDataPtr);
         let data = &mut *ptr;
-        let flags = data.flags;
-        if (flags & GcFlags::COLOR_MASK) == GcFlags::WHITE {
-            data.flags -= GcFlags::COLOR_MASK;
+        // Old objects are treated as already marked during a minor collection.
+        if (*self.0).minor && data.flags.contains(GcFlags::OLD) {
+            return;
+        }
+        if !Heap::is_marked(ptr as GcDataPtr) {
+            Heap::set_marked(ptr as GcDataPtr, true);
             data.flags |= GcFlags::GRAY;
             (*self.0).trace_queue.push(ptr as *mut GcData<()>);
         }
     }
+
+    /// Panics if `ptr` was allocated by another context. Objects from different contexts must
+    /// never reference each other, since each context frees its objects without regard to the
+    /// others.
+    #[inline]
+    #[track_caller]
+    pub(crate) unsafe fn assert_owns(&self, ptr: GcDataPtr) {
+        if !(*self.0).heap.owns(ptr) {
+            panic!(
+                "A `{}` at {:?} was used with a context other than the one that allocated it",
+                ((*ptr).vtbl.type_name)(),
+                GcData::value_ptr(ptr),
+            );
+        }
+    }
+}
+
+impl GcContextData {
+    /// Panics if a value about to be rooted in this context references objects allocated by
+    /// another context.
+    ///
+    /// This traces the whole value, so it is only checked in debug builds and with the `verify`
+    /// feature. Otherwise, a foreign object is only caught once a collection traces it.
+    #[track_caller]
+    pub(crate) unsafe fn assert_owns_value(this: *mut Self, value: *const (), vtbl: &GcVtbl) {
+        if !cfg!(any(debug_assertions, feature = "verify")) {
+            return;
+        }
+        let ctx = GcContext(this);
+        for (object, _) in ctx.record_value_edges(value, vtbl, true) {
+            ctx.assert_owns(object);
+        }
+    }
+
+    pub(crate) unsafe fn insert_root(&mut self, root: *mut GcRootData) {
+        if !self.roots.is_null() {
+            (*self.roots).prev = root;
+        }
+        (*root).next = self.roots;
+        self.roots = root;
+        self.num_roots += 1;
+    }
+
+    pub(crate) unsafe fn remove_root(&mut self, root: *const GcRootData) {
+        if !(*root).next.is_null() {
+            (*(*root).next).prev = (*root).prev;
+        }
+        if !(*root).prev.is_null() {
+            (*(*root).prev).next = (*root).next;
+        } else {
+            self.roots = (*root).next;
+        }
+        self.num_roots -= 1;
+    }
 }
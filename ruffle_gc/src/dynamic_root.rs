@@ -0,0 +1,162 @@
+use crate::{Gc, GcContext, GcContextData, GcDataPtr, GcHeapRoot, GcLifetime, Trace};
+use generational_arena::{Arena, Index};
+use std::{
+    cell::{Cell, RefCell},
+    marker::PhantomData,
+    rc::Rc,
+};
+
+/// A set of roots that can be held without borrowing the context, such as by native callbacks
+/// and timers.
+///
+/// Objects are rooted with `stash`, which returns a `'static` handle that keeps the object alive
+/// until every clone of the handle has been dropped. The whole set is a single entry in the
+/// context's root list, and the handles are just indices into it, so this is cheaper than a
+/// `GcHeapRoot` per object.
+pub struct GcDynamicRootSet {
+    table: Rc<RootTable>,
+    _root: GcHeapRoot<RootSlots>,
+}
+
+/// An object rooted by a `GcDynamicRootSet`, which can be fetched with the set's `GcContext`.
+///
+/// `T` is the type of the object with any lifetime, such as `Node<'static>`.
+pub struct GcDynamicRoot<T> {
+    slot: Rc<Slot>,
+    _phantom: PhantomData<*const T>,
+}
+
+struct RootTable {
+    ctx: *mut GcContextData,
+    slots: RefCell<Arena<GcDataPtr>>,
+    /// Cleared once the set is dropped, after which its objects are no longer rooted.
+    rooted: Cell<bool>,
+}
+
+/// The slots of a root set, traced as a single root.
+struct RootSlots(Rc<RootTable>);
+
+/// A slot shared by every clone of a `GcDynamicRoot`, freed when the last one is dropped.
+struct Slot {
+    table: Rc<RootTable>,
+    index: Index,
+}
+
+impl GcDynamicRootSet {
+    /// Creates an empty root set registered with `ctx`. It must be dropped before the context is
+    /// destroyed.
+    pub fn new(ctx: &mut GcContext) -> Self {
+        let table = Rc::new(RootTable {
+            ctx: ctx.data(),
+            slots: RefCell::new(Arena::new()),
+            rooted: Cell::new(true),
+        });
+        let root = GcHeapRoot::new(ctx, |_| RootSlots(table.clone()));
+        Self { table, _root: root }
+    }
+
+    /// Roots an object until every clone of the returned handle has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ctx` isn't the set's context, or if the object was allocated by another context.
+    #[track_caller]
+    pub fn stash<T>(&self, ctx: &GcContext, gc: Gc<'_, T>) -> GcDynamicRoot<T::Aged>
+    where
+        T: GcLifetime<'static>,
+    {
+        assert!(
+            self.table.ctx == ctx.data(),
+            "Stashed an object with a different context than its root set"
+        );
+        unsafe {
+            ctx.assert_owns(gc.ptr);
+        }
+        let index = self.table.slots.borrow_mut().insert(gc.ptr);
+        GcDynamicRoot {
+            slot: Rc::new(Slot {
+                table: self.table.clone(),
+                index,
+            }),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the number of objects rooted by the set.
+    pub fn len(&self) -> usize {
+        self.table.slots.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for GcDynamicRootSet {
+    fn drop(&mut self) {
+        self.table.rooted.set(false);
+    }
+}
+
+impl<T> GcDynamicRoot<T> {
+    /// Returns the rooted object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ctx` isn't the context of the set that the object was stashed in, or if that set
+    /// has been dropped.
+    pub fn fetch<'gc>(&self, ctx: &'gc GcContext) -> Gc<'gc, T::Aged>
+    where
+        T: GcLifetime<'gc>,
+    {
+        let table = &self.slot.table;
+        assert!(
+            table.rooted.get(),
+            "Fetched a dynamic root after its root set was dropped"
+        );
+        assert!(
+            table.ctx == ctx.data(),
+            "Fetched a dynamic root with a different context than its root set"
+        );
+        Gc {
+            ptr: self.ptr(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns `true` if both handles root the same object.
+    pub fn ptr_eq(&self, other: &GcDynamicRoot<T>) -> bool {
+        self.ptr() == other.ptr()
+    }
+
+    fn ptr(&self) -> GcDataPtr {
+        self.slot.table.slots.borrow()[self.slot.index]
+    }
+}
+
+impl<T> Clone for GcDynamicRoot<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.table.slots.borrow_mut().remove(self.index);
+    }
+}
+
+unsafe impl Trace for RootSlots {
+    unsafe fn trace(&self, ctx: &mut GcContext) {
+        for (_, &ptr) in self.0.slots.borrow().iter() {
+            ctx.trace(ptr);
+        }
+    }
+}
+
+unsafe impl GcLifetime<'_> for RootSlots {
+    type Aged = Self;
+}
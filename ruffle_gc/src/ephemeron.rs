@@ -0,0 +1,154 @@
+use crate::{Gc, GcContext, GcDataPtr, GcLifetime, Trace};
+use std::{
+    cell::{Cell, UnsafeCell},
+    collections::HashMap,
+    fmt::{self, Debug},
+    rc::Rc,
+};
+
+/// A map whose keys are held weakly.
+///
+/// A value is only kept alive while its key is reachable from outside of the map, even if the
+/// value references its own key. Entries are removed once their key has been collected. This is
+/// the behavior of an ephemeron table, such as ActionScript's `Dictionary` with weak keys.
+///
+/// Keys are compared by identity. The map must be stored in managed data and mutated through
+/// `Gc::borrow_mut`, so that the collector can see any newly inserted entries.
+pub struct GcEphemeronMap<'gc, K, V> {
+    table: Rc<EphemeronTable<'gc, K, V>>,
+}
+
+struct EphemeronTable<'gc, K, V> {
+    entries: UnsafeCell<HashMap<GcDataPtr, (Gc<'gc, K>, V)>>,
+    /// Whether the table is registered with the context for the current collection.
+    registered: Cell<bool>,
+}
+
+/// A type-erased ephemeron table, registered with the context while it is being marked.
+pub(crate) trait Ephemeron {
+    /// Traces the values of entries whose keys have been marked.
+    unsafe fn trace_values(&self, ctx: &mut GcContext);
+
+    /// Removes entries whose keys weren't marked, and unregisters the table.
+    unsafe fn prune(&self, ctx: &GcContext);
+}
+
+impl<'gc, K, V> GcEphemeronMap<'gc, K, V> {
+    pub fn new() -> Self {
+        Self {
+            table: Rc::new(EphemeronTable {
+                entries: UnsafeCell::new(HashMap::new()),
+                registered: Cell::new(false),
+            }),
+        }
+    }
+
+    /// Inserts a value, returning the value previously associated with `key`.
+    pub fn insert(&mut self, key: Gc<'gc, K>, value: V) -> Option<V> {
+        self.entries_mut()
+            .insert(key.ptr, (key, value))
+            .map(|(_, value)| value)
+    }
+
+    pub fn get(&self, key: Gc<'gc, K>) -> Option<&V> {
+        self.entries().get(&key.ptr).map(|(_, value)| value)
+    }
+
+    pub fn get_mut(&mut self, key: Gc<'gc, K>) -> Option<&mut V> {
+        self.entries_mut().get_mut(&key.ptr).map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, key: Gc<'gc, K>) -> Option<V> {
+        self.entries_mut().remove(&key.ptr).map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: Gc<'gc, K>) -> bool {
+        self.entries().contains_key(&key.ptr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Gc<'gc, K>, &V)> {
+        self.entries().values().map(|(key, value)| (*key, value))
+    }
+
+    fn entries(&self) -> &HashMap<GcDataPtr, (Gc<'gc, K>, V)> {
+        // The collector only modifies the table while it has exclusive access to the context.
+        unsafe { &*self.table.entries.get() }
+    }
+
+    fn entries_mut(&mut self) -> &mut HashMap<GcDataPtr, (Gc<'gc, K>, V)> {
+        unsafe { &mut *self.table.entries.get() }
+    }
+}
+
+impl<'gc, K, V> Default for GcEphemeronMap<'gc, K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'gc, K, V: Debug> Debug for GcEphemeronMap<'gc, K, V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_map()
+            .entries(self.iter().map(|(key, value)| (key.as_ptr(), value)))
+            .finish()
+    }
+}
+
+impl<'gc, K, V: Trace> Ephemeron for EphemeronTable<'gc, K, V> {
+    unsafe fn trace_values(&self, ctx: &mut GcContext) {
+        for (&key, (_, value)) in &*self.entries.get() {
+            if ctx.is_live(key) {
+                value.trace(ctx);
+            }
+        }
+    }
+
+    unsafe fn prune(&self, ctx: &GcContext) {
+        (*self.entries.get()).retain(|&key, _| ctx.is_live(key));
+        self.registered.set(false);
+    }
+}
+
+unsafe impl<'gc, K: 'gc, V: Trace + 'gc> Trace for GcEphemeronMap<'gc, K, V> {
+    unsafe fn trace(&self, ctx: &mut GcContext) {
+        // A heap snapshot sees every value, since it isn't part of a collection. Keys are only
+        // reported when every pointer is needed, such as to rebuild the remembered set.
+        if ctx.is_recording() {
+            let keys = ctx.is_recording_keys();
+            for (key, value) in (*self.table.entries.get()).values() {
+                if keys {
+                    ctx.trace(key.ptr);
+                }
+                value.trace(ctx);
+            }
+            return;
+        }
+
+        // Entries whose keys haven't been marked yet are revisited once marking is otherwise
+        // complete.
+        self.table.trace_values(ctx);
+        if !self.table.registered.replace(true) {
+            let table: Rc<dyn Ephemeron + 'gc> = self.table.clone();
+            ctx.register_ephemeron(std::mem::transmute::<
+                Rc<dyn Ephemeron + 'gc>,
+                Rc<dyn Ephemeron>,
+            >(table));
+        }
+    }
+}
+
+unsafe impl<'a, 'gc, K, V> GcLifetime<'a> for GcEphemeronMap<'gc, K, V>
+where
+    K: GcLifetime<'a> + 'a,
+    V: GcLifetime<'a>,
+{
+    type Aged = GcEphemeronMap<'a, K::Aged, V::Aged>;
+}
@@ -0,0 +1,32 @@
+use crate::{Gc, GcContext, GcDataPtr, Trace};
+use std::marker::PhantomData;
+
+/// Types that run code after becoming unreachable.
+///
+/// Unlike `Drop`, which runs in the middle of the sweep, finalizers run once the sweep is
+/// complete and have full access to the context. The object and everything it references are kept
+/// alive until its finalizer has run, so they may be accessed freely, at the cost of being
+/// deallocated one cycle later. Weak pointers to the object are cleared before it is finalized.
+///
+/// Each object is finalized at most once. If a finalizer makes its object reachable again, it is
+/// deallocated without being finalized the next time it becomes unreachable. Finalizers are not run
+/// for objects that are still alive when the context is destroyed. A panicking finalizer unwinds
+/// out of the call that collected its object, and the remaining finalizers run after the next
+/// collection.
+///
+/// A finalizer is registered by deriving `Gc` with `#[gc(finalize)]`, or by allocating with
+/// `GcContext::allocate_with_finalizer`.
+pub trait Finalize: Trace {
+    fn finalize(this: Gc<'_, Self>, ctx: &mut GcContext);
+}
+
+/// Calls the finalizer of a type-erased object.
+pub(crate) unsafe fn finalize<T: Finalize>(ptr: GcDataPtr, ctx: &mut GcContext) {
+    T::finalize(
+        Gc {
+            ptr,
+            _phantom: PhantomData,
+        },
+        ctx,
+    );
+}
@@ -1,4 +1,4 @@
-use crate::{GcContext, GcLifetime, GcWeak, Trace, WeakId};
+use crate::{finalize, Finalize, GcContext, GcLifetime, GcWeak, Trace};
 use bitflags::bitflags;
 use std::{
     cell::UnsafeCell,
@@ -22,48 +22,62 @@ impl<'a, T> Clone for Gc<'a, T> {
 impl<'a, T> Copy for Gc<'a, T> {}
 
 impl<'a, T> Gc<'a, T> {
+    #[track_caller]
     pub fn get<'b>(self, _: &'b GcContext) -> T::Aged
     where
         T: GcLifetime<'b>,
         T::Aged: Copy,
         'a: 'b,
     {
-        unsafe { *(self.ptr as *mut T::Aged) }
+        unsafe {
+            GcData::assert_live(self.ptr);
+            *(*(self.ptr as *mut GcData<T::Aged>)).value.get()
+        }
     }
 
     /// Immutably borrows the inner value pointed to by this pointer.
     ///
     /// This requires immutable access to the `GcContext` to ensure that no other managed data is
     /// mutated for the duration of the borrow.
+    #[track_caller]
     pub fn borrow<'b>(self, _: &'b GcContext) -> &'b T::Aged
     where
         T: GcLifetime<'b>,
         'a: 'b,
     {
-        unsafe { &*(*(self.ptr as *mut GcData<T::Aged>)).value.get() }
+        unsafe {
+            GcData::assert_live(self.ptr);
+            &*(*(self.ptr as *mut GcData<T::Aged>)).value.get()
+        }
     }
 
     /// Mutably borrows the inner value pointed to by this pointer.
     ///
     /// This requires mutable access to the `GcContext` to ensure that no other managed data can
-    /// be accessed for the duration of the borrow.
-    pub fn borrow_mut<'b>(self, _: &'b mut GcContext) -> &'b mut T::Aged
+    /// be accessed for the duration of the borrow. A write barrier is applied so that an
+    /// in-progress collection will see any pointers stored into the value.
+    #[track_caller]
+    pub fn borrow_mut<'b>(self, ctx: &'b mut GcContext) -> &'b mut T::Aged
     where
         T: GcLifetime<'b>,
         'a: 'b,
     {
-        unsafe { &mut *(*(self.ptr as *mut GcData<T::Aged>)).value.get() }
+        unsafe {
+            GcData::assert_live(self.ptr);
+            ctx.write_barrier(self.ptr);
+            &mut *(*(self.ptr as *mut GcData<T::Aged>)).value.get()
+        }
     }
 
+    /// Creates a weak pointer to the inner value, which can only be used with `ctx`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value was allocated by another context.
+    #[track_caller]
     pub fn downgrade(self, ctx: &GcContext) -> GcWeak<'a, T> {
-        let weak = unsafe { (*self.ptr).weak };
-        let id = if let Some(id) = weak {
-            id
-        } else {
-            ctx.add_weak(self.ptr)
-        };
         GcWeak {
-            id,
+            id: ctx.weak_id(self.ptr),
             _phantom: Default::default(),
         }
     }
@@ -75,7 +89,7 @@ impl<'a, T> Gc<'a, T> {
 
     /// Returns a pointer to the underlying value.
     pub fn as_ptr(self) -> *const T {
-        self.ptr as *mut T as *const T
+        unsafe { (*(self.ptr as *mut GcData<T>)).value.get() }
     }
 }
 
@@ -95,30 +109,127 @@ unsafe impl<'a, T: Trace> Trace for Gc<'a, T> {
 
 bitflags! {
     /// Flags stored into the header of a garbage collected allocation.
-    pub(crate) struct GcFlags: u8 {
-        const WHITE  = 0b00;
-        const GRAY   = 0b01;
-        const BLACK  = 0b10;
-        const COLOR_MASK = 0b11;
+    ///
+    /// Whether an object is marked is kept in its page's mark bitmap instead. A marked object is
+    /// gray while it is in the trace queue, and black otherwise.
+    pub(crate) struct GcFlags: u16 {
+        const GRAY = 0b1;
+        /// The object has an entry in the context's weak id table.
+        const HAS_WEAK = 0b10;
 
         const NEEDS_TRACE = 0b100;
+
+        /// The object has been promoted out of the nursery.
+        const OLD = 0b1000;
+        /// The object is in the remembered set and may point into the nursery.
+        const REMEMBERED = 0b1_0000;
+        /// The number of minor collections a young object has survived.
+        const AGE_MASK = 0b1110_0000;
+        /// The object has been freed, and is being held in quarantine by the `poison` feature.
+        const DEAD = 0b1_0000_0000;
+    }
+}
+
+impl GcFlags {
+    const AGE_SHIFT: u32 = 5;
+
+    /// The largest age that can be stored in the flags.
+    pub(crate) const MAX_AGE: u8 = (Self::AGE_MASK.bits >> Self::AGE_SHIFT) as u8;
+
+    pub(crate) fn age(self) -> u8 {
+        ((self & Self::AGE_MASK).bits >> Self::AGE_SHIFT) as u8
+    }
+
+    pub(crate) fn set_age(&mut self, age: u8) {
+        *self -= Self::AGE_MASK;
+        self.bits |= u16::from(age.min(Self::MAX_AGE)) << Self::AGE_SHIFT;
     }
 }
 
 pub(crate) type GcDataPtr = *mut GcData<()>;
 
+/// The header of a garbage collected allocation, followed by its value.
+///
+/// This is kept to a vtable pointer and flags, since it is paid for by every object.
 #[repr(C)]
 pub struct GcData<T> {
-    pub(crate) vtbl: GcVtbl,
+    pub(crate) vtbl: &'static GcVtbl,
     pub(crate) flags: GcFlags,
-    pub(crate) weak: Option<WeakId>,
-    pub(crate) next: GcDataPtr,
     pub(crate) value: UnsafeCell<T>,
 }
 
-/// The virtual method table stored with garbage collected data.
+impl GcData<()> {
+    /// Returns a pointer to the value of a type-erased allocation.
+    ///
+    /// The value's offset depends on its alignment, so it can't be read through `GcData<()>`.
+    #[inline]
+    pub(crate) unsafe fn value_ptr(this: GcDataPtr) -> *const () {
+        (this as *const u8).add((*this).vtbl.value_offset) as *const ()
+    }
+
+    /// Panics if the object has been freed. Only objects quarantined by the `poison` feature are
+    /// detected, so this is a no-op without it.
+    #[inline]
+    #[track_caller]
+    pub(crate) unsafe fn assert_live(this: GcDataPtr) {
+        #[cfg(feature = "poison")]
+        if (*this).flags.contains(GcFlags::DEAD) {
+            panic!(
+                "Use of a freed `{}` at {:?}. It was collected while still referenced, so a root \
+                 or a traced field is probably missing.",
+                ((*this).vtbl.type_name)(),
+                GcData::value_ptr(this),
+            );
+        }
+        #[cfg(not(feature = "poison"))]
+        let _ = this;
+    }
+}
+
+/// The virtual method table shared by all garbage collected data of the same type.
 #[repr(C)]
 pub struct GcVtbl {
     pub(crate) trace: unsafe fn(&(), &mut GcContext),
-    pub(crate) dealloc: unsafe fn(*mut ()),
+    pub(crate) trace_fields: unsafe fn(&(), &mut GcContext),
+    pub(crate) drop: unsafe fn(*mut ()),
+    /// The size of the entire allocation, including the header.
+    pub(crate) size: usize,
+    /// The offset of the value from the start of the allocation.
+    pub(crate) value_offset: usize,
+    pub(crate) type_name: fn() -> &'static str,
+    pub(crate) finalize: Option<unsafe fn(GcDataPtr, &mut GcContext)>,
+}
+
+impl GcVtbl {
+    pub(crate) const fn new<T: Trace>(
+        finalize: Option<unsafe fn(GcDataPtr, &mut GcContext)>,
+    ) -> Self {
+        unsafe {
+            GcVtbl {
+                trace: std::mem::transmute::<
+                    unsafe fn(&T, &mut GcContext),
+                    unsafe fn(&(), &mut GcContext),
+                >(T::trace),
+                trace_fields: std::mem::transmute::<
+                    unsafe fn(&T, &mut GcContext),
+                    unsafe fn(&(), &mut GcContext),
+                >(T::trace_fields),
+                drop: std::mem::transmute::<unsafe fn(*mut GcData<T>), unsafe fn(*mut ())>(
+                    T::drop_in_place,
+                ),
+                size: std::mem::size_of::<GcData<T>>(),
+                value_offset: std::mem::offset_of!(GcData<T>, value),
+                type_name: std::any::type_name::<T>,
+                finalize,
+            }
+        }
+    }
+
+    /// Returns the vtable for `T` with its finalizer registered.
+    ///
+    /// This is used by `#[gc(finalize)]`.
+    #[doc(hidden)]
+    pub fn finalizable<T: Finalize>() -> &'static GcVtbl {
+        const { &GcVtbl::new::<T>(Some(finalize::<T>)) }
+    }
 }
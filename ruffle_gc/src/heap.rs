@@ -0,0 +1,354 @@
+use crate::GcDataPtr;
+use std::{
+    alloc::{self, Layout},
+    mem, ptr,
+};
+
+/// The size and alignment of a page.
+///
+/// Every object starts within the first `PAGE_SIZE` bytes of its page, so the page containing an
+/// object can be found by masking its address.
+pub(crate) const PAGE_SIZE: usize = 64 * 1024;
+
+/// The minimum alignment of every slot.
+const MIN_ALIGN: usize = 16;
+
+/// The alignment of the first slot of a page with a size class. A slot is aligned to the largest
+/// power of two dividing its size, up to this.
+const MAX_SLOT_ALIGN: usize = 64;
+
+/// The slot sizes of pages shared by several objects. Objects larger than a quarter of a page get
+/// a page of their own.
+const SIZE_CLASSES: [usize; 22] = [
+    16, 32, 48, 64, 96, 128, 160, 192, 256, 320, 384, 512, 768, 1024, 1536, 2048, 3072, 4096, 6144,
+    8192, 12288, 16384,
+];
+
+const MAX_SLOTS: usize = PAGE_SIZE / MIN_ALIGN;
+const BITMAP_WORDS: usize = MAX_SLOTS / 64;
+
+/// The header at the start of every page, followed by its slots.
+///
+/// Per-object state that the sweep needs is kept in side bitmaps here rather than in the object
+/// headers, so that sweeping a page only touches the headers of objects being freed.
+#[repr(C)]
+pub(crate) struct Page {
+    /// The heap the page belongs to, which identifies the context that allocated its objects.
+    heap: *const Heap,
+    /// The size of the whole page allocation. Equal to `PAGE_SIZE` unless this is a large page.
+    size: usize,
+    slot_size: usize,
+    slot_count: usize,
+    /// The offset of the first slot from the start of the page.
+    first_slot: usize,
+    /// The size class index, or `None` for a large page holding a single object.
+    class: Option<usize>,
+    /// The number of slots that have never been allocated, starting from the end.
+    bump: usize,
+    free_list: *mut FreeSlot,
+    /// The number of allocated slots, including retired slots that haven't been reused yet.
+    live: usize,
+    /// Whether this page is in its size class's list of pages with free slots.
+    available: bool,
+    /// Whether this page still has to be swept in the current major cycle.
+    pub(crate) unswept: bool,
+    alloc_bits: [u64; BITMAP_WORDS],
+    mark_bits: [u64; BITMAP_WORDS],
+    young_bits: [u64; BITMAP_WORDS],
+}
+
+/// A free slot, linked into its page's free list.
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+impl Page {
+    fn bitmap_words(&self) -> usize {
+        self.slot_count.div_ceil(64)
+    }
+
+    unsafe fn slot(this: *mut Page, index: usize) -> GcDataPtr {
+        (this as *mut u8).add((*this).first_slot + index * (*this).slot_size) as GcDataPtr
+    }
+
+    unsafe fn index_of(this: *mut Page, ptr: GcDataPtr) -> usize {
+        (ptr as usize - this as usize - (*this).first_slot) / (*this).slot_size
+    }
+
+    /// Calls `f` with every object in this page.
+    pub(crate) unsafe fn for_each_object(this: *mut Page, mut f: impl FnMut(GcDataPtr)) {
+        for word in 0..(*this).bitmap_words() {
+            let mut bits = (*this).alloc_bits[word];
+            while bits != 0 {
+                f(Page::slot(this, word * 64 + bits.trailing_zeros() as usize));
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Calls `f` with every old object in this page that wasn't marked, then unmarks every old
+    /// object. Young objects are left for the nursery sweep.
+    pub(crate) unsafe fn sweep(this: *mut Page, mut f: impl FnMut(GcDataPtr)) {
+        for word in 0..(*this).bitmap_words() {
+            let mut dead =
+                (*this).alloc_bits[word] & !(*this).mark_bits[word] & !(*this).young_bits[word];
+            while dead != 0 {
+                f(Page::slot(this, word * 64 + dead.trailing_zeros() as usize));
+                dead &= dead - 1;
+            }
+            (*this).mark_bits[word] &= (*this).young_bits[word];
+        }
+        (*this).unswept = false;
+    }
+
+    /// Returns whether `ptr` points to the start of an allocated slot in this page.
+    pub(crate) unsafe fn is_allocated(this: *mut Page, ptr: GcDataPtr) -> bool {
+        let offset = (ptr as usize).wrapping_sub(this as usize + (*this).first_slot);
+        let index = offset / (*this).slot_size;
+        offset.is_multiple_of((*this).slot_size)
+            && index < (*this).slot_count
+            && (*this).alloc_bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the allocated object whose slot contains `addr`, if there is one.
+    pub(crate) unsafe fn object_containing(this: *mut Page, addr: usize) -> Option<GcDataPtr> {
+        let offset = addr.wrapping_sub(this as usize + (*this).first_slot);
+        let index = offset / (*this).slot_size;
+        let allocated =
+            index < (*this).slot_count && (*this).alloc_bits[index / 64] & (1 << (index % 64)) != 0;
+        allocated.then(|| Page::slot(this, index))
+    }
+
+    /// Returns the number of allocated slots in this page.
+    pub(crate) unsafe fn live(this: *mut Page) -> usize {
+        (*this).live
+    }
+}
+
+/// Storage for all objects in a context.
+pub(crate) struct Heap {
+    pages: Vec<*mut Page>,
+    /// Pages with free slots for each size class.
+    available: [Vec<*mut Page>; SIZE_CLASSES.len()],
+}
+
+impl Heap {
+    pub(crate) fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            available: Default::default(),
+        }
+    }
+
+    pub(crate) fn pages(&self) -> &[*mut Page] {
+        &self.pages
+    }
+
+    /// Returns whether `ptr` was allocated by this heap.
+    #[inline]
+    pub(crate) unsafe fn owns(&self, ptr: GcDataPtr) -> bool {
+        ptr::eq((*Heap::page_of(ptr)).heap, self)
+    }
+
+    /// Returns the page containing `ptr`.
+    #[inline]
+    pub(crate) fn page_of(ptr: GcDataPtr) -> *mut Page {
+        let offset = ptr as usize & (PAGE_SIZE - 1);
+        (ptr as *mut u8).wrapping_sub(offset) as *mut Page
+    }
+
+    /// Allocates an uninitialized slot for an object with the given layout.
+    pub(crate) unsafe fn alloc(&mut self, layout: Layout) -> GcDataPtr {
+        let class = SIZE_CLASSES
+            .iter()
+            .position(|&size| size >= layout.size() && size.is_multiple_of(layout.align()))
+            .filter(|_| layout.align() <= MAX_SLOT_ALIGN);
+        let page = match class {
+            Some(class) => match self.available[class].last() {
+                Some(&page) => page,
+                None => {
+                    let page =
+                        self.new_page(PAGE_SIZE, SIZE_CLASSES[class], MAX_SLOT_ALIGN, Some(class));
+                    (*page).available = true;
+                    self.available[class].push(page);
+                    page
+                }
+            },
+            None => {
+                let first_slot = align_up(mem::size_of::<Page>(), layout.align());
+                assert!(
+                    first_slot < PAGE_SIZE,
+                    "Alignment too large for GC allocation"
+                );
+                let size = align_up(first_slot + layout.size(), PAGE_SIZE);
+                self.new_page(size, layout.size(), layout.align(), None)
+            }
+        };
+
+        let ptr = if !(*page).free_list.is_null() {
+            let slot = (*page).free_list;
+            (*page).free_list = (*slot).next;
+            slot as GcDataPtr
+        } else {
+            let index = (*page).slot_count - (*page).bump;
+            (*page).bump -= 1;
+            Page::slot(page, index)
+        };
+        let index = Page::index_of(page, ptr);
+        (*page).alloc_bits[index / 64] |= 1 << (index % 64);
+        (*page).live += 1;
+
+        if (*page).free_list.is_null() && (*page).bump == 0 && (*page).available {
+            // Small objects are always allocated from the last available page.
+            self.available[(*page).class.unwrap()].pop();
+            (*page).available = false;
+        }
+        ptr
+    }
+
+    /// Returns a slot to its page. The object must already have been dropped.
+    #[cfg_attr(feature = "poison", allow(dead_code))]
+    pub(crate) unsafe fn free(&mut self, ptr: GcDataPtr) {
+        Heap::retire(ptr);
+        self.reuse(ptr);
+    }
+
+    /// Removes a slot from its page's bitmaps, without making it available for reuse or letting
+    /// its page be released. The object must already have been dropped.
+    pub(crate) unsafe fn retire(ptr: GcDataPtr) {
+        let page = Heap::page_of(ptr);
+        let index = Page::index_of(page, ptr);
+        let bit = 1 << (index % 64);
+        (*page).alloc_bits[index / 64] &= !bit;
+        (*page).mark_bits[index / 64] &= !bit;
+        (*page).young_bits[index / 64] &= !bit;
+    }
+
+    /// Makes a retired slot available for reuse.
+    pub(crate) unsafe fn reuse(&mut self, ptr: GcDataPtr) {
+        let page = Heap::page_of(ptr);
+        (*page).live -= 1;
+
+        if let Some(class) = (*page).class {
+            let slot = ptr as *mut FreeSlot;
+            (*slot).next = (*page).free_list;
+            (*page).free_list = slot;
+            if !(*page).available {
+                (*page).available = true;
+                self.available[class].push(page);
+            }
+        }
+    }
+
+    /// Deallocates every page without any objects in it.
+    pub(crate) unsafe fn release_empty_pages(&mut self) {
+        for available in &mut self.available {
+            available.retain(|&page| (*page).live > 0);
+        }
+        self.pages.retain(|&page| {
+            if (*page).live > 0 {
+                return true;
+            }
+            Heap::free_page(page);
+            false
+        });
+    }
+
+    /// Deallocates every page. Objects must already have been dropped.
+    pub(crate) unsafe fn release_all_pages(&mut self) {
+        for page in self.pages.drain(..) {
+            Heap::free_page(page);
+        }
+        for available in &mut self.available {
+            available.clear();
+        }
+    }
+
+    /// Flags every page as needing to be swept.
+    pub(crate) unsafe fn start_sweep(&mut self) {
+        for &page in &self.pages {
+            (*page).unswept = true;
+        }
+    }
+
+    #[inline]
+    pub(crate) unsafe fn is_marked(ptr: GcDataPtr) -> bool {
+        let page = Heap::page_of(ptr);
+        let index = Page::index_of(page, ptr);
+        (*page).mark_bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    #[inline]
+    pub(crate) unsafe fn set_marked(ptr: GcDataPtr, marked: bool) {
+        let page = Heap::page_of(ptr);
+        let index = Page::index_of(page, ptr);
+        let bit = 1 << (index % 64);
+        if marked {
+            (*page).mark_bits[index / 64] |= bit;
+        } else {
+            (*page).mark_bits[index / 64] &= !bit;
+        }
+    }
+
+    #[inline]
+    pub(crate) unsafe fn set_young(ptr: GcDataPtr, young: bool) {
+        let page = Heap::page_of(ptr);
+        let index = Page::index_of(page, ptr);
+        let bit = 1 << (index % 64);
+        if young {
+            (*page).young_bits[index / 64] |= bit;
+        } else {
+            (*page).young_bits[index / 64] &= !bit;
+        }
+    }
+
+    unsafe fn new_page(
+        &mut self,
+        size: usize,
+        slot_size: usize,
+        align: usize,
+        class: Option<usize>,
+    ) -> *mut Page {
+        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+        let page = alloc::alloc(layout) as *mut Page;
+        if page.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        let first_slot = align_up(mem::size_of::<Page>(), align);
+        let slot_count = if class.is_some() {
+            (size - first_slot) / slot_size
+        } else {
+            1
+        };
+        ptr::write(
+            page,
+            Page {
+                heap: self,
+                size,
+                slot_size,
+                slot_count,
+                first_slot,
+                class,
+                bump: slot_count,
+                free_list: ptr::null_mut(),
+                live: 0,
+                available: false,
+                unswept: false,
+                alloc_bits: [0; BITMAP_WORDS],
+                mark_bits: [0; BITMAP_WORDS],
+                young_bits: [0; BITMAP_WORDS],
+            },
+        );
+        self.pages.push(page);
+        page
+    }
+
+    unsafe fn free_page(page: *mut Page) {
+        let layout = Layout::from_size_align((*page).size, PAGE_SIZE).unwrap();
+        alloc::dealloc(page as *mut u8, layout);
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
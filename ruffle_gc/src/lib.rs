@@ -1,29 +1,61 @@
+mod arena;
+mod cell;
 mod context;
+mod dynamic_root;
+mod ephemeron;
+mod finalize;
 mod gc;
+mod heap;
 mod lifetime;
+mod observer;
 mod root;
+mod snapshot;
+#[cfg(target_os = "linux")]
+mod stack;
 mod trace;
 mod weak;
+mod weak_value_map;
 
-pub use context::GcContext;
+pub use arena::{GcArena, GcMutation};
+pub use cell::GcCell;
+pub use context::{
+    GcContext, GcNursery, GcPacing, GcPhase, GcStats, GcUntracedEdge, GcVerifyError,
+};
+pub use dynamic_root::{GcDynamicRoot, GcDynamicRootSet};
+pub use ephemeron::GcEphemeronMap;
+pub use finalize::Finalize;
 pub use gc::{Gc, GcVtbl};
 pub use lifetime::GcLifetime;
-pub use root::{GcHeapRoot, GcRoot, GcRootData};
+#[cfg(feature = "log")]
+pub use observer::LogObserver;
+#[cfg(feature = "tracing")]
+pub use observer::TracingObserver;
+pub use observer::{GcCollection, GcObserver};
+pub use root::{GcHeapRoot, GcRoot, GcRootData, GcRootKind};
+pub use snapshot::{
+    GcHeapSnapshot, GcObjectEntry, GcObjectInfo, GcObjectSnapshot, GcPathHop, GcRetainingPath,
+    GcRootInfo, GcSnapshotDiff, GcTypeDiff,
+};
 pub use trace::Trace;
-pub use weak::GcWeak;
+pub use weak::{GcCleanupToken, GcWeak, GcWeakId};
+pub use weak_value_map::GcWeakValueMap;
 
 pub use ruffle_gc_derive::Gc;
 
+pub(crate) use context::GcContextData;
+pub(crate) use ephemeron::Ephemeron;
+pub(crate) use finalize::finalize;
 pub(crate) use gc::{GcData, GcDataPtr, GcFlags};
-pub(crate) use weak::WeakId;
+pub(crate) use weak::{Cleanup, CleanupFn, WeakId};
+pub(crate) use weak_value_map::WeakValues;
 
-/// Creates a new GC root on the stack.
+/// Creates a new GC root on the stack, registered with the given context.
 #[macro_export]
 macro_rules! pin_root {
-    ($name:ident $(,)?) => {
+    ($ctx:expr, $name:ident $(,)?) => {
         let mut $name = $name;
         let mut $name = unsafe { ruffle_gc::GcRoot::new($name) };
         #[allow(unused_mut)]
-        let mut $name = $name.pin();
+        let mut $name = $name.pin(&$ctx);
     };
 }
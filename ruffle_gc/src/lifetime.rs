@@ -1,9 +1,19 @@
 use crate::Gc;
-use std::{cell::*, collections::*, mem, num::*};
+use std::{collections::*, mem, num::*};
 
+/// Types whose garbage collected lifetime can be rebound to a different lifetime.
+///
+/// # Safety
+///
+/// `Aged` must be the same type as `Self` with every `Gc` lifetime replaced by `'a`.
 pub unsafe trait GcLifetime<'a> {
     type Aged;
 
+    /// Rebinds the lifetime of `self` to `'a`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that any managed data referenced by `self` stays alive for `'a`.
     unsafe fn change_lifetime(self) -> Self::Aged
     where
         Self: Sized,
@@ -127,28 +137,14 @@ where
     type Aged = Result<T::Aged, E::Aged>;
 }
 
-unsafe impl<'a, T> GcLifetime<'a> for Cell<T>
-where
-    T: GcLifetime<'a>,
-{
-    type Aged = Cell<T::Aged>;
-}
-
-unsafe impl<'a, T> GcLifetime<'a> for RefCell<T>
-where
-    T: GcLifetime<'a>,
-{
-    type Aged = RefCell<T::Aged>;
-}
-
-unsafe impl<'a, 'b, T> GcLifetime<'a> for BinaryHeap<T>
+unsafe impl<'a, T> GcLifetime<'a> for BinaryHeap<T>
 where
     T: GcLifetime<'a>,
 {
     type Aged = BinaryHeap<T::Aged>;
 }
 
-unsafe impl<'a, 'b, K, V> GcLifetime<'a> for BTreeMap<K, V>
+unsafe impl<'a, K, V> GcLifetime<'a> for BTreeMap<K, V>
 where
     K: GcLifetime<'a>,
     V: GcLifetime<'a>,
@@ -156,14 +152,14 @@ where
     type Aged = BTreeMap<K::Aged, V::Aged>;
 }
 
-unsafe impl<'a, 'b, T> GcLifetime<'a> for BTreeSet<T>
+unsafe impl<'a, T> GcLifetime<'a> for BTreeSet<T>
 where
     T: GcLifetime<'a>,
 {
     type Aged = BTreeSet<T::Aged>;
 }
 
-unsafe impl<'a, 'b, K, V> GcLifetime<'a> for HashMap<K, V>
+unsafe impl<'a, K, V> GcLifetime<'a> for HashMap<K, V>
 where
     K: GcLifetime<'a>,
     V: GcLifetime<'a>,
@@ -171,28 +167,28 @@ where
     type Aged = HashMap<K::Aged, V::Aged>;
 }
 
-unsafe impl<'a, 'b, T> GcLifetime<'a> for HashSet<T>
+unsafe impl<'a, T> GcLifetime<'a> for HashSet<T>
 where
     T: GcLifetime<'a>,
 {
     type Aged = HashSet<T::Aged>;
 }
 
-unsafe impl<'a, 'b, T> GcLifetime<'a> for LinkedList<T>
+unsafe impl<'a, T> GcLifetime<'a> for LinkedList<T>
 where
     T: GcLifetime<'a>,
 {
     type Aged = LinkedList<T::Aged>;
 }
 
-unsafe impl<'a, 'b, T> GcLifetime<'a> for Vec<T>
+unsafe impl<'a, T> GcLifetime<'a> for Vec<T>
 where
     T: GcLifetime<'a>,
 {
     type Aged = Vec<T::Aged>;
 }
 
-unsafe impl<'a, 'b, T> GcLifetime<'a> for VecDeque<T>
+unsafe impl<'a, T> GcLifetime<'a> for VecDeque<T>
 where
     T: GcLifetime<'a>,
 {
@@ -0,0 +1,94 @@
+use crate::GcPhase;
+
+/// The kind of a collection cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcCollection {
+    /// A collection of the whole heap.
+    Major,
+    /// A collection of the nursery only.
+    Minor,
+}
+
+/// Hooks called by the collector, installed with `GcContext::set_observer`.
+///
+/// Every method does nothing by default. Objects are identified by the address of their value,
+/// as returned by `Gc::as_ptr`. Observers are called in the middle of a collection and must not
+/// access managed data.
+pub trait GcObserver {
+    /// Called when a collection cycle starts. Major and minor cycles are numbered separately.
+    fn cycle_start(&mut self, _collection: GcCollection, _number: u32) {}
+
+    /// Called when a collection cycle ends, before any finalizers or cleanups are run.
+    fn cycle_end(&mut self, _collection: GcCollection, _number: u32) {}
+
+    /// Called when a major cycle moves to a new phase.
+    fn phase_change(&mut self, _phase: GcPhase) {}
+
+    /// Called when an object is allocated. `size` includes the object header.
+    fn allocate(&mut self, _ptr: *const (), _size: usize) {}
+
+    /// Called before an object is dropped and deallocated.
+    fn free(&mut self, _ptr: *const (), _size: usize) {}
+}
+
+/// An observer that reports collections with the `log` crate.
+///
+/// Cycles and phase changes are logged at the debug level, and individual objects at the trace
+/// level.
+#[cfg(feature = "log")]
+#[derive(Debug, Default)]
+pub struct LogObserver;
+
+#[cfg(feature = "log")]
+impl GcObserver for LogObserver {
+    fn cycle_start(&mut self, collection: GcCollection, number: u32) {
+        log::debug!("{:?} collect {} start", collection, number);
+    }
+
+    fn cycle_end(&mut self, collection: GcCollection, number: u32) {
+        log::debug!("{:?} collect {} end", collection, number);
+    }
+
+    fn phase_change(&mut self, phase: GcPhase) {
+        log::debug!("GC phase {:?}", phase);
+    }
+
+    fn allocate(&mut self, ptr: *const (), size: usize) {
+        log::trace!("Allocate {:?} ({} bytes)", ptr, size);
+    }
+
+    fn free(&mut self, ptr: *const (), size: usize) {
+        log::trace!("Free {:?} ({} bytes)", ptr, size);
+    }
+}
+
+/// An observer that reports collections as `tracing` events.
+///
+/// Cycles and phase changes are recorded at the debug level, and individual objects at the trace
+/// level.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default)]
+pub struct TracingObserver;
+
+#[cfg(feature = "tracing")]
+impl GcObserver for TracingObserver {
+    fn cycle_start(&mut self, collection: GcCollection, number: u32) {
+        tracing::debug!(?collection, number, "collect start");
+    }
+
+    fn cycle_end(&mut self, collection: GcCollection, number: u32) {
+        tracing::debug!(?collection, number, "collect end");
+    }
+
+    fn phase_change(&mut self, phase: GcPhase) {
+        tracing::debug!(?phase, "phase change");
+    }
+
+    fn allocate(&mut self, ptr: *const (), size: usize) {
+        tracing::trace!(?ptr, size, "allocate");
+    }
+
+    fn free(&mut self, ptr: *const (), size: usize) {
+        tracing::trace!(?ptr, size, "free");
+    }
+}
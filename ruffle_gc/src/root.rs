@@ -1,16 +1,32 @@
-use crate::{GcContext, GcLifetime, GcVtbl, Trace};
+use crate::{GcContext, GcContextData, GcLifetime, GcVtbl, Trace};
 use std::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
     ptr,
 };
 
+/// The kinds of root that keep managed data alive, as reported by `GcContext::heap_snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GcRootKind {
+    /// A `GcRoot` pinned on the stack with `pin_root!`.
+    Stack,
+    /// A `GcHeapRoot`.
+    Heap,
+    /// An object waiting for its finalizer to run.
+    Finalizer,
+    /// A value held by a cleanup callback registered with `GcContext::register_cleanup`.
+    Cleanup,
+}
+
 #[repr(C)]
 pub struct GcRootData {
-    pub(crate) vtbl: GcVtbl,
+    pub(crate) vtbl: &'static GcVtbl,
     pub(crate) next: *mut GcRootData,
     pub(crate) prev: *mut GcRootData,
     pub(crate) value: *mut (),
+    /// The context this root is registered with, or null if it hasn't been pinned yet.
+    pub(crate) ctx: *mut GcContextData,
+    pub(crate) kind: GcRootKind,
 }
 
 #[repr(C)]
@@ -20,31 +36,59 @@ pub struct GcRoot<T: ?Sized> {
 }
 
 impl<T> GcRoot<T> {
+    /// Creates a new, unpinned root. Use the `pin_root!` macro instead of calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// The returned root must be pinned before the next collection, or the managed data that it
+    /// references may be collected.
     pub unsafe fn new<'a>(value: T) -> GcRoot<T::Aged>
     where
         T: GcLifetime<'a> + Trace,
     {
+        GcRoot::with_vtbl(value.change_lifetime(), T::vtbl())
+    }
+
+    /// Creates a new, unpinned root traced by `vtbl`, without rebinding the value's lifetime.
+    pub(crate) fn with_vtbl(value: T, vtbl: &'static GcVtbl) -> Self {
         GcRoot {
             inner: GcRootData {
-                vtbl: T::vtbl(),
+                vtbl,
                 next: ptr::null_mut(),
                 prev: ptr::null_mut(),
                 value: ptr::null_mut(),
+                ctx: ptr::null_mut(),
+                kind: GcRootKind::Stack,
             },
-            value: UnsafeCell::new(value.change_lifetime()),
+            value: UnsafeCell::new(value),
         }
     }
 
-    pub fn pin<'a>(&'a mut self) -> &'a T
+    /// Registers this root with `ctx`, keeping its value alive until the root is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the root is already registered with another context. In debug builds, or with
+    /// the `verify` feature, also panics if the value references managed data allocated by
+    /// another context.
+    #[track_caller]
+    pub fn pin<'a>(&'a mut self, ctx: &GcContext) -> &'a T
     where
         T: GcLifetime<'a>,
         T: Trace,
     {
         unsafe {
-            let value_ptr = self.value.get() as *mut ();
-            self.inner.value = value_ptr;
-            let ptr = &mut self.inner as *mut GcRootData;
-            GcContext::get().insert_root(ptr);
+            assert!(
+                self.inner.ctx.is_null() || self.inner.ctx == ctx.data(),
+                "Pinned a root with a different context than it is registered with"
+            );
+            if self.inner.ctx.is_null() {
+                let value_ptr = self.value.get() as *mut ();
+                GcContextData::assert_owns_value(ctx.data(), value_ptr, self.inner.vtbl);
+                self.inner.value = value_ptr;
+                self.inner.ctx = ctx.data();
+                (*ctx.data()).insert_root(&mut self.inner);
+            }
             &*self.value.get()
         }
     }
@@ -53,7 +97,9 @@ impl<T> GcRoot<T> {
 impl Drop for GcRootData {
     fn drop(&mut self) {
         unsafe {
-            GcContext::get().remove_root(self as *const GcRootData);
+            if !self.ctx.is_null() {
+                (*self.ctx).remove_root(self);
+            }
         }
     }
 }
@@ -62,23 +108,48 @@ impl Drop for GcRootData {
 pub struct GcHeapRoot<T>(pub(crate) Box<GcRoot<T>>);
 
 impl<T> GcHeapRoot<T> {
-    pub fn new<'a>(value: T) -> GcHeapRoot<T::Aged>
+    /// Creates a root on the heap holding the value returned by `f`.
+    ///
+    /// The value is created inside a closure so that it can borrow the context it is being
+    /// rooted in, e.g. `GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(value))`.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, or with the `verify` feature, panics if the value references managed data
+    /// allocated by another context.
+    #[track_caller]
+    pub fn new<'a, 'b, F>(ctx: &'a mut GcContext, f: F) -> GcHeapRoot<T::Aged>
     where
-        T: GcLifetime<'a> + Trace,
+        F: FnOnce(&'a mut GcContext) -> T,
+        T: GcLifetime<'b> + Trace,
     {
         unsafe {
-            let root_data = GcRoot::new(value);
-            let mut boxed = Box::new(root_data);
-            let value_ptr: *mut () = boxed.value.get() as *mut ();
-            boxed.inner.value = value_ptr;
-            let ptr = &mut boxed.inner as *mut GcRootData;
-            GcContext::get().insert_root(ptr);
-            GcHeapRoot(boxed)
+            let data = ctx.data();
+            let root_data = GcRoot::new(f(ctx));
+            GcHeapRoot::register(data, root_data)
         }
     }
+
+    /// Moves an unpinned root onto the heap and registers it with a context.
+    ///
+    /// # Safety
+    ///
+    /// Any managed data referenced by the root must still be alive, and must have been allocated by
+    /// the context.
+    #[track_caller]
+    pub(crate) unsafe fn register(data: *mut GcContextData, root_data: GcRoot<T>) -> Self {
+        let mut boxed = Box::new(root_data);
+        let value_ptr: *mut () = boxed.value.get() as *mut ();
+        GcContextData::assert_owns_value(data, value_ptr, boxed.inner.vtbl);
+        boxed.inner.value = value_ptr;
+        boxed.inner.ctx = data;
+        boxed.inner.kind = GcRootKind::Heap;
+        (*data).insert_root(&mut boxed.inner);
+        GcHeapRoot(boxed)
+    }
 }
 
-impl<'a, T> Deref for GcHeapRoot<T> {
+impl<T> Deref for GcHeapRoot<T> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -86,7 +157,7 @@ impl<'a, T> Deref for GcHeapRoot<T> {
     }
 }
 
-impl<'a, T> DerefMut for GcHeapRoot<T> {
+impl<T> DerefMut for GcHeapRoot<T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.0.deref().value.get() }
@@ -0,0 +1,442 @@
+use crate::GcRootKind;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+};
+
+/// A copy of the object graph, taken with `GcContext::heap_snapshot`.
+///
+/// Objects are identified by the address of their value, as returned by `Gc::as_ptr`.
+#[derive(Debug, Clone, Default)]
+pub struct GcHeapSnapshot {
+    /// Every object in the heap, including unreachable objects that haven't been collected yet.
+    pub objects: Vec<GcObjectInfo>,
+    /// Every root registered with the context, followed by the objects kept alive internally by
+    /// pending finalizers and cleanups.
+    pub roots: Vec<GcRootInfo>,
+}
+
+/// An object in a `GcHeapSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcObjectInfo {
+    pub id: usize,
+    pub type_name: &'static str,
+    /// The size of the allocation, including the object header.
+    pub size: usize,
+    /// The ids of the objects this object references, in tracing order.
+    pub edges: Vec<usize>,
+}
+
+/// A root in a `GcHeapSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcRootInfo {
+    pub kind: GcRootKind,
+    /// The type of the rooted value.
+    pub type_name: &'static str,
+    /// The ids of the objects referenced by the rooted value.
+    pub edges: Vec<usize>,
+}
+
+/// A chain of references from a root to an object, returned by `GcContext::retaining_paths`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcRetainingPath {
+    pub root_kind: GcRootKind,
+    /// The type of the rooted value.
+    pub root_type_name: &'static str,
+    /// The objects along the path, from the one referenced by the root to the queried object.
+    pub hops: Vec<GcPathHop>,
+}
+
+/// An object on a `GcRetainingPath`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcPathHop {
+    /// The field of the previous object or root that references this object, if it is known.
+    pub field: Option<&'static str>,
+    pub type_name: &'static str,
+    pub id: usize,
+}
+
+impl fmt::Display for GcRetainingPath {
+    /// Formats the path as, e.g., `heap root Player -> .stage Stage -> .children Vec<Child>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} root {}", self.root_kind.name(), self.root_type_name)?;
+        for hop in &self.hops {
+            write!(f, " ->")?;
+            if let Some(field) = hop.field {
+                write!(f, " .{}", field)?;
+            }
+            write!(f, " {}", hop.type_name)?;
+        }
+        Ok(())
+    }
+}
+
+impl GcHeapSnapshot {
+    /// Writes the snapshot as a Graphviz graph.
+    ///
+    /// Objects are labelled with their type and size, and roots are drawn as boxes.
+    pub fn write_dot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "digraph heap {{")?;
+        for (i, root) in self.roots.iter().enumerate() {
+            writeln!(
+                w,
+                "    root{} [shape=box, label=\"{} root: {}\"];",
+                i,
+                root.kind.name(),
+                escape(root.type_name)
+            )?;
+            for edge in &root.edges {
+                writeln!(w, "    root{} -> obj{:x};", i, edge)?;
+            }
+        }
+        for object in &self.objects {
+            writeln!(
+                w,
+                "    obj{:x} [label=\"{}\\n{} bytes\"];",
+                object.id,
+                escape(object.type_name),
+                object.size
+            )?;
+            for edge in &object.edges {
+                writeln!(w, "    obj{:x} -> obj{:x};", object.id, edge)?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes the snapshot as JSON.
+    ///
+    /// The output has the following schema, where ids are the numeric object ids used in
+    /// `GcObjectInfo::id`:
+    ///
+    /// ```text
+    /// {
+    ///   "objects": [{ "id": number, "type": string, "size": number, "edges": [id] }],
+    ///   "roots": [{ "kind": string, "type": string, "edges": [id] }]
+    /// }
+    /// ```
+    ///
+    /// The kind of a root is one of `"stack"`, `"heap"`, `"finalizer"` or `"cleanup"`.
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        write!(w, "{{\"objects\":[")?;
+        for (i, object) in self.objects.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            write!(
+                w,
+                "{{\"id\":{},\"type\":\"{}\",\"size\":{},\"edges\":",
+                object.id,
+                escape(object.type_name),
+                object.size
+            )?;
+            write_json_ids(&mut w, &object.edges)?;
+            write!(w, "}}")?;
+        }
+        write!(w, "],\"roots\":[")?;
+        for (i, root) in self.roots.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            write!(
+                w,
+                "{{\"kind\":\"{}\",\"type\":\"{}\",\"edges\":",
+                root.kind.name(),
+                escape(root.type_name)
+            )?;
+            write_json_ids(&mut w, &root.edges)?;
+            write!(w, "}}")?;
+        }
+        writeln!(w, "]}}")
+    }
+
+    /// Writes the snapshot in the `.heapsnapshot` format used by the Memory tab of Chrome's
+    /// developer tools.
+    ///
+    /// Roots are grouped under a synthetic node for each kind of root. Unreachable objects that
+    /// haven't been collected yet are included without any retainers.
+    pub fn write_heapsnapshot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        const NODE_FIELDS: usize = 6;
+        const NODE_SYNTHETIC: usize = 9;
+        const NODE_OBJECT: usize = 3;
+        const EDGE_ELEMENT: usize = 1;
+
+        let mut strings = StringTable::default();
+        // Each node is `[type, name, id, self_size, edge_count, trace_node_id]`, and each edge is
+        // `[type, index, to_node]`, where `to_node` is the node's offset into `nodes`.
+        let mut nodes: Vec<usize> = Vec::new();
+        let mut edges: Vec<usize> = Vec::new();
+
+        let kinds = [
+            GcRootKind::Stack,
+            GcRootKind::Heap,
+            GcRootKind::Finalizer,
+            GcRootKind::Cleanup,
+        ];
+        let root_node = |i| 1 + kinds.len() + i;
+        let first_object_node = root_node(self.roots.len());
+        let object_nodes: HashMap<usize, usize> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.id, first_object_node + i))
+            .collect();
+        // Synthetic nodes are numbered from 1. Object ids are addresses, so they can't collide.
+        let mut next_id = 1;
+        let push_node = |nodes: &mut Vec<usize>, ty, name, id, size, edge_count| {
+            nodes.extend_from_slice(&[ty, name, id, size, edge_count, 0]);
+        };
+        let push_edges = |edges: &mut Vec<usize>, targets: &mut dyn Iterator<Item = usize>| {
+            let mut count = 0;
+            for target in targets {
+                count += 1;
+                edges.extend_from_slice(&[EDGE_ELEMENT, count, target * NODE_FIELDS]);
+            }
+            count
+        };
+
+        // The synthetic root of the graph, which references each group of roots.
+        let count = push_edges(&mut edges, &mut (1..=kinds.len()));
+        push_node(
+            &mut nodes,
+            NODE_SYNTHETIC,
+            strings.get(""),
+            next_id,
+            0,
+            count,
+        );
+        next_id += 1;
+        for kind in kinds {
+            let mut members = (0..self.roots.len())
+                .filter(|&i| self.roots[i].kind == kind)
+                .map(root_node);
+            let count = push_edges(&mut edges, &mut members);
+            let name = strings.get(kind.group_name());
+            push_node(&mut nodes, NODE_SYNTHETIC, name, next_id, 0, count);
+            next_id += 1;
+        }
+        for root in &self.roots {
+            let mut targets = root
+                .edges
+                .iter()
+                .filter_map(|id| object_nodes.get(id).copied());
+            let count = push_edges(&mut edges, &mut targets);
+            let name = strings.get(root.type_name);
+            push_node(&mut nodes, NODE_SYNTHETIC, name, next_id, 0, count);
+            next_id += 1;
+        }
+        for object in &self.objects {
+            let mut targets = object
+                .edges
+                .iter()
+                .filter_map(|id| object_nodes.get(id).copied());
+            let count = push_edges(&mut edges, &mut targets);
+            let name = strings.get(object.type_name);
+            push_node(&mut nodes, NODE_OBJECT, name, object.id, object.size, count);
+        }
+
+        write!(
+            w,
+            concat!(
+                "{{\"snapshot\":{{\"meta\":{{",
+                "\"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",",
+                "\"trace_node_id\"],",
+                "\"node_types\":[[\"hidden\",\"array\",\"string\",\"object\",\"code\",",
+                "\"closure\",\"regexp\",\"number\",\"native\",\"synthetic\",",
+                "\"concatenated string\",\"sliced string\",\"symbol\",\"bigint\"],",
+                "\"string\",\"number\",\"number\",\"number\",\"number\"],",
+                "\"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],",
+                "\"edge_types\":[[\"context\",\"element\",\"property\",\"internal\",",
+                "\"hidden\",\"shortcut\",\"weak\"],\"string_or_number\",\"node\"],",
+                "\"trace_function_info_fields\":[],\"trace_node_fields\":[],",
+                "\"sample_fields\":[],\"location_fields\":[]}},",
+                "\"node_count\":{},\"edge_count\":{},\"trace_function_count\":0}},"
+            ),
+            nodes.len() / NODE_FIELDS,
+            edges.len() / 3
+        )?;
+        write!(w, "\"nodes\":")?;
+        write_json_ids(&mut w, &nodes)?;
+        write!(w, ",\"edges\":")?;
+        write_json_ids(&mut w, &edges)?;
+        write!(
+            w,
+            ",\"trace_function_infos\":[],\"trace_tree\":[],\"samples\":[],\"locations\":[],"
+        )?;
+        write!(w, "\"strings\":[")?;
+        for (i, string) in strings.strings.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            write!(w, "\"{}\"", escape(string))?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+/// The identity, type and size of every object, taken with `GcContext::object_snapshot`.
+#[derive(Debug, Clone, Default)]
+pub struct GcObjectSnapshot {
+    /// Every object in the heap, sorted by id.
+    pub objects: Vec<GcObjectEntry>,
+}
+
+/// An object in a `GcObjectSnapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcObjectEntry {
+    /// An id that is unique for the lifetime of the context.
+    pub id: u64,
+    pub type_name: &'static str,
+    /// The size of the allocation, including the object header.
+    pub size: usize,
+}
+
+/// The objects allocated and freed between two `GcObjectSnapshot`s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcSnapshotDiff {
+    /// The changes for each type with new or freed objects, sorted by decreasing growth in bytes.
+    pub types: Vec<GcTypeDiff>,
+    pub new_objects: usize,
+    pub new_bytes: usize,
+    pub freed_objects: usize,
+    pub freed_bytes: usize,
+}
+
+/// The objects of one type allocated and freed between two `GcObjectSnapshot`s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcTypeDiff {
+    pub type_name: &'static str,
+    pub new_objects: usize,
+    pub new_bytes: usize,
+    pub freed_objects: usize,
+    pub freed_bytes: usize,
+}
+
+impl GcObjectSnapshot {
+    /// Compares this snapshot with one taken later from the same context.
+    pub fn diff(&self, later: &GcObjectSnapshot) -> GcSnapshotDiff {
+        let mut types: HashMap<&'static str, GcTypeDiff> = HashMap::new();
+        fn type_diff<'a>(
+            types: &'a mut HashMap<&'static str, GcTypeDiff>,
+            type_name: &'static str,
+        ) -> &'a mut GcTypeDiff {
+            types.entry(type_name).or_insert_with(|| GcTypeDiff {
+                type_name,
+                ..Default::default()
+            })
+        }
+
+        // Both lists are sorted by id, so they can be merged in one pass.
+        let mut old = self.objects.iter().peekable();
+        let mut new = later.objects.iter().peekable();
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a.id == b.id => {
+                    old.next();
+                    new.next();
+                }
+                (Some(a), b) if b.is_none_or(|b| a.id < b.id) => {
+                    let diff = type_diff(&mut types, a.type_name);
+                    diff.freed_objects += 1;
+                    diff.freed_bytes += a.size;
+                    old.next();
+                }
+                (_, Some(b)) => {
+                    let diff = type_diff(&mut types, b.type_name);
+                    diff.new_objects += 1;
+                    diff.new_bytes += b.size;
+                    new.next();
+                }
+                _ => break,
+            }
+        }
+
+        let mut types: Vec<_> = types.into_values().collect();
+        types.sort_by_key(|diff| (std::cmp::Reverse(diff.byte_growth()), diff.type_name));
+        GcSnapshotDiff {
+            new_objects: types.iter().map(|diff| diff.new_objects).sum(),
+            new_bytes: types.iter().map(|diff| diff.new_bytes).sum(),
+            freed_objects: types.iter().map(|diff| diff.freed_objects).sum(),
+            freed_bytes: types.iter().map(|diff| diff.freed_bytes).sum(),
+            types,
+        }
+    }
+}
+
+impl GcSnapshotDiff {
+    /// Returns the change in the number of objects.
+    pub fn object_growth(&self) -> isize {
+        self.new_objects as isize - self.freed_objects as isize
+    }
+
+    /// Returns the change in the size of the heap, in bytes.
+    pub fn byte_growth(&self) -> isize {
+        self.new_bytes as isize - self.freed_bytes as isize
+    }
+}
+
+impl GcTypeDiff {
+    /// Returns the change in the number of objects of this type.
+    pub fn object_growth(&self) -> isize {
+        self.new_objects as isize - self.freed_objects as isize
+    }
+
+    /// Returns the change in the size of objects of this type, in bytes.
+    pub fn byte_growth(&self) -> isize {
+        self.new_bytes as isize - self.freed_bytes as isize
+    }
+}
+
+impl GcRootKind {
+    fn name(self) -> &'static str {
+        match self {
+            GcRootKind::Stack => "stack",
+            GcRootKind::Heap => "heap",
+            GcRootKind::Finalizer => "finalizer",
+            GcRootKind::Cleanup => "cleanup",
+        }
+    }
+
+    fn group_name(self) -> &'static str {
+        match self {
+            GcRootKind::Stack => "(GcRoot stack roots)",
+            GcRootKind::Heap => "(GcHeapRoot roots)",
+            GcRootKind::Finalizer => "(Pending finalizers)",
+            GcRootKind::Cleanup => "(Cleanup held values)",
+        }
+    }
+}
+
+/// The deduplicated strings of a `.heapsnapshot`, referenced by index.
+#[derive(Default)]
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, usize>,
+}
+
+impl<'a> StringTable<'a> {
+    fn get(&mut self, string: &'a str) -> usize {
+        let strings = &mut self.strings;
+        *self.indices.entry(string).or_insert_with(|| {
+            strings.push(string);
+            strings.len() - 1
+        })
+    }
+}
+
+fn write_json_ids<W: Write>(w: &mut W, ids: &[usize]) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, id) in ids.iter().enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "{}", id)?;
+    }
+    write!(w, "]")
+}
+
+/// Escapes a string for use in a quoted DOT or JSON string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,93 @@
+use std::{cell::Cell, mem::MaybeUninit, ptr};
+
+thread_local! {
+    /// The highest address of the current thread's stack, once it has been looked up.
+    static STACK_BASE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Calls `f` with every word on the current thread's stack, from the caller's frame to the base
+/// of the stack.
+///
+/// Callee-saved registers are spilled onto the stack first, so that pointers which the caller
+/// only holds in registers are seen too.
+#[inline(never)]
+pub(crate) unsafe fn scan_stack(mut f: impl FnMut(usize)) {
+    let registers = spill_registers();
+    let word = std::mem::size_of::<usize>();
+    let mut addr = registers.as_ptr() as usize;
+    let base = stack_base();
+    while addr + word <= base {
+        f(ptr::read_volatile(addr as *const usize));
+        addr += word;
+    }
+    std::hint::black_box(&registers);
+}
+
+/// Returns the highest address of the current thread's stack. Stacks grow downwards on every
+/// supported platform.
+unsafe fn stack_base() -> usize {
+    let base = STACK_BASE.with(Cell::get);
+    if base != 0 {
+        return base;
+    }
+    let mut attr = MaybeUninit::<libc::pthread_attr_t>::uninit();
+    let result = libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr());
+    assert_eq!(
+        result, 0,
+        "Couldn't find the bounds of the current thread's stack"
+    );
+    let mut addr = ptr::null_mut();
+    let mut size = 0;
+    libc::pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size);
+    libc::pthread_attr_destroy(attr.as_mut_ptr());
+    let base = addr as usize + size;
+    STACK_BASE.with(|cell| cell.set(base));
+    base
+}
+
+/// Copies the callee-saved registers into an array on the stack.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn spill_registers() -> [usize; 6] {
+    let mut registers = [0; 6];
+    unsafe {
+        std::arch::asm!(
+            "mov [{0}], rbx",
+            "mov [{0} + 8], rbp",
+            "mov [{0} + 16], r12",
+            "mov [{0} + 24], r13",
+            "mov [{0} + 32], r14",
+            "mov [{0} + 40], r15",
+            in(reg) registers.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+    registers
+}
+
+/// Copies the callee-saved registers into an array on the stack.
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn spill_registers() -> [usize; 12] {
+    let mut registers = [0; 12];
+    unsafe {
+        std::arch::asm!(
+            "stp x19, x20, [{0}]",
+            "stp x21, x22, [{0}, #16]",
+            "stp x23, x24, [{0}, #32]",
+            "stp x25, x26, [{0}, #48]",
+            "stp x27, x28, [{0}, #64]",
+            "stp x29, x30, [{0}, #80]",
+            in(reg) registers.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+    registers
+}
+
+/// Registers are assumed to have been saved on the stack by the calls leading here.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline(always)]
+fn spill_registers() -> [usize; 1] {
+    [0]
+}
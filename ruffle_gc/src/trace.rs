@@ -1,30 +1,57 @@
 use crate::{GcContext, GcData, GcVtbl};
-use std::{cell::*, collections::*, marker::*, num::*};
+use std::{collections::*, marker::*, num::*};
 
 /// Types that may be stored in garbage collected pointers.
+///
+/// # Safety
+///
+/// `trace` must visit every `Gc` pointer reachable from `self`. A missed pointer will be collected
+/// while still in use. The pointers must also not be changed through a shared reference, except
+/// through a `GcCell`, since that would skip the write barrier.
 pub unsafe trait Trace
 where
     Self: Sized,
 {
+    /// Marks all managed data directly referenced by `self`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the garbage collector during a collection.
     #[allow(unused_variables)]
     unsafe fn trace(&self, ctx: &mut GcContext) {}
 
+    /// Like `trace`, but labels each reference with the name of the field it was found in by
+    /// calling `GcContext::set_edge_label`. This is implemented by `#[derive(Gc)]`, and is only
+    /// used to describe the heap, such as by `GcContext::retaining_paths`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the garbage collector.
+    unsafe fn trace_fields(&self, ctx: &mut GcContext) {
+        self.trace(ctx);
+    }
+
+    /// Returns `false` if this type can never contain `Gc` pointers.
+    ///
+    /// # Safety
+    ///
+    /// Returning `false` for a type containing `Gc` pointers will cause them to be collected.
     unsafe fn needs_trace() -> bool {
         true
     }
 
-    fn vtbl() -> GcVtbl {
-        unsafe {
-            GcVtbl {
-                trace: std::mem::transmute(Self::trace as unsafe fn(_, _)),
-                dealloc: std::mem::transmute(Self::dealloc as unsafe fn(_)),
-            }
-        }
+    /// Returns the vtable for this type, of which there is one per type.
+    fn vtbl() -> &'static GcVtbl {
+        const { &GcVtbl::new::<Self>(None) }
     }
 
-    unsafe fn dealloc(this: *mut GcData<Self>) {
-        Box::from_raw(this);
-        // Box dropped here
+    /// Drops a garbage collected allocation in place. Its memory is reclaimed by the heap.
+    ///
+    /// # Safety
+    ///
+    /// `this` must have been allocated by a `GcContext` and must not be used afterwards.
+    unsafe fn drop_in_place(this: *mut GcData<Self>) {
+        std::ptr::drop_in_place(this);
     }
 }
 
@@ -100,18 +127,6 @@ unsafe impl<T: Trace, E: Trace> Trace for Result<T, E> {
     }
 }
 
-unsafe impl<T: Copy + Trace> Trace for Cell<T> {
-    unsafe fn trace(&self, ctx: &mut GcContext) {
-        self.get().trace(ctx)
-    }
-}
-
-unsafe impl<T: Trace> Trace for RefCell<T> {
-    unsafe fn trace(&self, ctx: &mut GcContext) {
-        self.borrow().trace(ctx)
-    }
-}
-
 unsafe impl<T: Trace> Trace for BinaryHeap<T> {
     unsafe fn trace(&self, ctx: &mut GcContext) {
         for t in self {
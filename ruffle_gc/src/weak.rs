@@ -1,4 +1,4 @@
-use crate::{Gc, GcContext, GcData, GcLifetime, Trace};
+use crate::{Gc, GcContext, GcContextData, GcData, GcDataPtr, GcLifetime, GcRetainingPath, Trace};
 use std::marker::PhantomData;
 
 /// A weak pointer to memory managed by the garbage collector.
@@ -6,13 +6,39 @@ use std::marker::PhantomData;
 /// A `GcWeak` pointer will not prevent the pointed-to data from being collected. Therefore,
 /// attempting to borrow the data via `borrow` or `upgrade` may return `None` if the data
 /// has been collecting.
+///
+/// A weak pointer can only be used with the context that created it, and panics otherwise.
 #[repr(transparent)]
 pub struct GcWeak<'a, T> {
     pub(crate) id: WeakId,
     pub(crate) _phantom: PhantomData<&'a T>,
 }
 
-pub(crate) type WeakId = generational_arena::Index;
+/// The slot of a weak pointer in its context's weak table. The context is kept alongside the
+/// index, since another context's table may have a different object in the same slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct WeakId {
+    pub(crate) ctx: *const GcContextData,
+    pub(crate) index: generational_arena::Index,
+}
+
+/// Identifies the object referenced by a weak pointer. Every `GcWeak` to the same object has the
+/// same id, and ids are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GcWeakId(pub(crate) WeakId);
+
+/// Identifies a callback registered with `GcContext::register_cleanup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcCleanupToken(pub(crate) generational_arena::Index);
+
+/// A callback to run with a held value once its target has been collected.
+pub(crate) struct Cleanup {
+    pub(crate) target: WeakId,
+    pub(crate) held: GcDataPtr,
+    pub(crate) callback: CleanupFn,
+}
+
+pub(crate) type CleanupFn = fn(Gc<'_, ()>, &mut GcContext);
 
 impl<'a, T> GcWeak<'a, T> {
     /// Attempts to upgrade the weak pointer to a `Gc`.
@@ -20,15 +46,43 @@ impl<'a, T> GcWeak<'a, T> {
     /// This requires immutable to the `GcContext` to ensure that the inner value does not get
     /// collected while the `Gc` pointer is not rooted. Returns `None` if the inner value has
     /// already been collected.
+    #[track_caller]
     pub fn upgrade(self, ctx: &'a GcContext) -> Option<Gc<'a, T>> {
         ctx.get_weak(self)
     }
 
+    /// Returns whether the inner value is still alive, without borrowing it.
+    #[track_caller]
+    pub fn is_alive(self, ctx: &GcContext) -> bool {
+        ctx.get_weak(self).is_some()
+    }
+
+    /// Returns whether the inner value has been collected.
+    #[track_caller]
+    pub fn is_dead(self, ctx: &GcContext) -> bool {
+        !self.is_alive(ctx)
+    }
+
+    /// Finds the shortest chains of references from a root to the inner value. Returns an empty
+    /// list if it has already been collected. See `GcContext::retaining_paths`.
+    #[track_caller]
+    pub fn retaining_paths(self, ctx: &GcContext, limit: usize) -> Vec<GcRetainingPath> {
+        ctx.get_weak(self)
+            .map_or_else(Vec::new, |gc| ctx.retaining_paths(gc, limit))
+    }
+
+    /// Returns the id of the object this weak pointer refers to, which can be matched against
+    /// `GcContext::cleared_weaks`.
+    pub fn id(self) -> GcWeakId {
+        GcWeakId(self.id)
+    }
+
     /// Attempts to borrow the inner value pointed to by the weak pointer.
     ///
     /// This requires immutable to the `GcContext` to ensure that the inner value does not get
     /// collected until the borrow is complete. Returns `None` if the inner value has already been
     /// collected.
+    #[track_caller]
     pub fn borrow<'b>(self, ctx: &'b GcContext) -> Option<&'b T::Aged>
     where
         T: GcLifetime<'b>,
@@ -39,16 +93,20 @@ impl<'a, T> GcWeak<'a, T> {
     }
 
     /// Attempts to mutably borrow the inner value pointed to by the weak pointer.
+    ///
     /// This requires mutable to the `GcContext` to ensure that no other managed data can be
     /// mutated until the borrow is complete. Returns `None` if the inner value has already been
     /// collected.
+    #[track_caller]
     pub fn borrow_mut<'b>(self, ctx: &'b mut GcContext) -> Option<&'b mut T::Aged>
     where
         T: GcLifetime<'b>,
         'a: 'b,
     {
-        ctx.get_weak(self)
-            .map(|gc| unsafe { &mut *(*(gc.ptr as *mut GcData<T::Aged>)).value.get() })
+        ctx.get_weak(self).map(|gc| unsafe {
+            ctx.write_barrier(gc.ptr);
+            &mut *(*(gc.ptr as *mut GcData<T::Aged>)).value.get()
+        })
     }
 }
 
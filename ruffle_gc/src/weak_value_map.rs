@@ -0,0 +1,135 @@
+use crate::{Gc, GcContext, GcDataPtr, GcLifetime, GcWeak, Trace};
+use generational_arena::Arena;
+use std::{
+    borrow::Borrow,
+    cell::{Cell, UnsafeCell},
+    collections::HashMap,
+    hash::Hash,
+    rc::Rc,
+};
+
+/// A map whose values are held weakly.
+///
+/// Entries are removed by the collector as soon as their value has been collected, rather than
+/// lingering until they are looked up. Keys are held strongly. This is useful for interning and
+/// caches.
+pub struct GcWeakValueMap<'gc, K, V> {
+    table: Rc<WeakValueTable<'gc, K, V>>,
+}
+
+struct WeakValueTable<'gc, K, V> {
+    entries: UnsafeCell<HashMap<K, GcWeak<'gc, V>>>,
+    /// The number of entries removed by the last collection.
+    pruned: Cell<usize>,
+}
+
+/// A type-erased weak-value table, registered with the context for as long as it exists.
+pub(crate) trait WeakValues {
+    /// Removes entries whose values have been cleared from `weaks`.
+    fn prune(&self, weaks: &Arena<GcDataPtr>);
+}
+
+impl<'gc, K: Eq + Hash, V> GcWeakValueMap<'gc, K, V> {
+    /// Creates a map that is pruned by collections of the given context.
+    pub fn new(ctx: &GcContext) -> Self
+    where
+        K: 'gc,
+        V: 'gc,
+    {
+        let table = Rc::new(WeakValueTable {
+            entries: UnsafeCell::new(HashMap::new()),
+            pruned: Cell::new(0),
+        });
+        let weak: std::rc::Weak<dyn WeakValues + 'gc> = Rc::downgrade(&table) as _;
+        unsafe {
+            ctx.register_weak_values(std::mem::transmute::<
+                std::rc::Weak<dyn WeakValues + 'gc>,
+                std::rc::Weak<dyn WeakValues>,
+            >(weak));
+        }
+        Self { table }
+    }
+
+    /// Inserts a value, returning the value previously associated with `key`.
+    pub fn insert(&mut self, key: K, value: GcWeak<'gc, V>) -> Option<GcWeak<'gc, V>> {
+        self.entries_mut().insert(key, value)
+    }
+
+    pub fn get<'b, Q>(&'b self, ctx: &'b GcContext, key: &Q) -> Option<Gc<'b, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries().get(key)?.upgrade(ctx)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<GcWeak<'gc, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries_mut().remove(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    /// Iterates over the entries whose values are still alive.
+    pub fn iter<'b>(&'b self, ctx: &'b GcContext) -> impl Iterator<Item = (&'b K, Gc<'b, V>)> {
+        self.entries()
+            .iter()
+            .filter_map(move |(key, value)| Some((key, value.upgrade(ctx)?)))
+    }
+
+    /// Returns the number of entries removed by the last collection.
+    pub fn pruned(&self) -> usize {
+        self.table.pruned.get()
+    }
+
+    fn entries(&self) -> &HashMap<K, GcWeak<'gc, V>> {
+        // The collector only modifies the table while it has exclusive access to the context.
+        unsafe { &*self.table.entries.get() }
+    }
+
+    fn entries_mut(&mut self) -> &mut HashMap<K, GcWeak<'gc, V>> {
+        unsafe { &mut *self.table.entries.get() }
+    }
+}
+
+impl<'gc, K, V> WeakValues for WeakValueTable<'gc, K, V> {
+    fn prune(&self, weaks: &Arena<GcDataPtr>) {
+        let entries = unsafe { &mut *self.entries.get() };
+        let len = entries.len();
+        entries.retain(|_, value| weaks.contains(value.id.index));
+        self.pruned.set(len - entries.len());
+    }
+}
+
+unsafe impl<'gc, K: Trace, V> Trace for GcWeakValueMap<'gc, K, V> {
+    unsafe fn trace(&self, ctx: &mut GcContext) {
+        for key in (*self.table.entries.get()).keys() {
+            key.trace(ctx);
+        }
+    }
+}
+
+unsafe impl<'a, 'gc, K, V> GcLifetime<'a> for GcWeakValueMap<'gc, K, V>
+where
+    K: GcLifetime<'a>,
+    V: GcLifetime<'a> + 'a,
+{
+    type Aged = GcWeakValueMap<'a, K::Aged, V::Aged>;
+}
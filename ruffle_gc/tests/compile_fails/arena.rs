@@ -0,0 +1,10 @@
+use ruffle_gc::{Gc, GcArena};
+
+fn main() {
+    let mut arena = GcArena::<Gc<'static, String>>::new(|mc| mc.allocate("Root".to_string())).unwrap();
+    // Managed data can't escape a mutation session, since it could be collected between sessions:
+    let data = arena.mutate(|mc, _| mc.allocate("Test".to_string()));
+    arena.collect();
+
+    println!("{:?}", data);
+}
@@ -5,8 +5,8 @@ use ruffle_gc::{Gc, GcContext, GcHeapRoot};
 fn main() {
     let mut ctx = GcContext::new().unwrap();
 
-    let root1 = GcHeapRoot::new(Root::new(&mut ctx, "Testing1"));
-    let root2 = GcHeapRoot::new(Root::new(&mut ctx, "Testing2"));
+    let root1 = GcHeapRoot::new(&mut ctx, |ctx| Root::new(ctx, "Testing1"));
+    let root2 = GcHeapRoot::new(&mut ctx, |ctx| Root::new(ctx, "Testing2"));
 
     // Okay: shared borrows of GcContext
     let s1 = root1.0.borrow(&ctx);
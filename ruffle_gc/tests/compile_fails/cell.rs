@@ -0,0 +1,16 @@
+#![allow(unused_variables)]
+
+use ruffle_gc::{Gc, GcContext};
+use std::cell::Cell;
+
+fn main() {
+    let mut ctx = GcContext::new().unwrap();
+    let node = ctx.allocate(Node { next: Cell::new(None) });
+}
+
+// Error: A `Cell` could be written to without a write barrier, so it can't hold pointers in
+// managed data. This must use a `GcCell` instead.
+#[derive(Gc)]
+struct Node<'a> {
+    next: Cell<Option<Gc<'a, Node<'a>>>>,
+}
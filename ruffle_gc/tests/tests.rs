@@ -1,16 +1,1143 @@
-use ruffle_gc::{GcContext, GcHeapRoot};
+use ruffle_gc::{
+    Finalize, Gc, GcArena, GcCell, GcCollection, GcContext, GcDynamicRoot, GcDynamicRootSet,
+    GcEphemeronMap, GcHeapRoot, GcLifetime, GcMutation, GcNursery, GcObserver, GcPacing, GcPhase,
+    GcRoot, GcRootKind, GcStats, GcWeakValueMap, Trace,
+};
+use std::{
+    cell::{Cell, RefCell},
+    num::NonZeroU32,
+    panic::AssertUnwindSafe,
+    rc::Rc,
+};
+
+thread_local! {
+    static CELL_NODE_DROPS: Cell<usize> = const { Cell::new(0) };
+    static FINALIZED: Cell<usize> = const { Cell::new(0) };
+    static CLEANED_UP: Cell<u32> = const { Cell::new(0) };
+}
+
+#[derive(Gc, Clone, Copy)]
+struct CellNode<'a>(Gc<'a, GcCell<CellNodeData<'a>>>);
+
+#[derive(Gc)]
+struct CellNodeData<'a> {
+    other: Option<CellNode<'a>>,
+}
+
+impl<'a> CellNode<'a> {
+    fn new(ctx: &'a mut GcContext) -> Self {
+        Self(ctx.allocate(GcCell::new(CellNodeData { other: None })))
+    }
+
+    fn other<'b>(self, ctx: &'b GcContext) -> Option<CellNode<'b>>
+    where
+        'a: 'b,
+    {
+        self.0.borrow(ctx).borrow().other
+    }
+}
+
+impl Drop for CellNodeData<'_> {
+    fn drop(&mut self) {
+        CELL_NODE_DROPS.with(|drops| drops.set(drops.get() + 1));
+    }
+}
 
 #[test]
 fn test_gc() {
     let mut ctx = GcContext::new().unwrap();
-    let object = GcHeapRoot::new(ctx.allocate("Test".to_string()));
+    let object = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("Test".to_string()));
+    ctx.collect();
+    assert_eq!(*object.borrow(&ctx), "Test");
+}
+
+#[test]
+fn test_collect_step() {
+    let mut ctx = GcContext::new().unwrap();
+    let object = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("Test".to_string()));
+    // Enough garbage to fill several pages, since pages are swept one at a time.
+    for i in 0..10_000 {
+        ctx.allocate(i);
+    }
+
+    assert_eq!(ctx.phase(), GcPhase::Idle);
+    ctx.collect_step(1);
+    assert_ne!(ctx.phase(), GcPhase::Idle);
+    let mut steps = 1;
+    while ctx.phase() != GcPhase::Idle {
+        ctx.collect_step(1);
+        steps += 1;
+    }
+    assert!(steps > 2);
+    assert_eq!(ctx.allocated_objects(), 1);
+    assert_eq!(*object.borrow(&ctx), "Test");
+
+    // `collect` finishes an in-progress cycle.
+    ctx.collect_step(1);
     ctx.collect();
+    assert_eq!(ctx.phase(), GcPhase::Idle);
     assert_eq!(*object.borrow(&ctx), "Test");
 }
 
+#[test]
+fn test_write_barrier() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+    {
+        let c = GcHeapRoot::new(&mut ctx, CellNode::new);
+        b.0.write(&ctx).other = Some(*c);
+    }
+
+    // Trace `a` so that it is black, leaving `b` and `c` untraced.
+    ctx.collect_step(1);
+    assert_eq!(ctx.phase(), GcPhase::Marking);
+
+    // Move `c` from the untraced `b` into the already traced `a`.
+    let c = b.other(&ctx);
+    a.0.write(&ctx).other = c;
+    b.0.write(&ctx).other = None;
+
+    ctx.collect();
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 0);
+    assert!(a.other(&ctx).is_some());
+}
+
+#[test]
+fn test_header_size() {
+    // The header is a vtable pointer and flags, padded to pointer alignment.
+    let mut ctx = GcContext::new().unwrap();
+    ctx.allocate(());
+    assert_eq!(ctx.allocated_bytes(), 2 * std::mem::size_of::<usize>());
+    ctx.allocate(0u64);
+    assert_eq!(ctx.allocated_bytes(), 5 * std::mem::size_of::<usize>());
+}
+
+#[derive(Gc, Clone, Copy)]
+struct TreeRef<'a>(Gc<'a, GcCell<Tree<'a>>>);
+
+#[derive(Gc)]
+enum Tree<'a> {
+    Leaf(u32),
+    Pair(Option<TreeRef<'a>>, Option<TreeRef<'a>>),
+}
+
+#[test]
+fn test_derive_enum() {
+    let mut ctx = GcContext::new().unwrap();
+    let pair = GcHeapRoot::new(&mut ctx, |ctx| {
+        TreeRef(ctx.allocate(GcCell::new(Tree::Pair(None, None))))
+    });
+    {
+        let a = GcHeapRoot::new(&mut ctx, |ctx| {
+            TreeRef(ctx.allocate(GcCell::new(Tree::Leaf(1))))
+        });
+        let b = GcHeapRoot::new(&mut ctx, |ctx| {
+            TreeRef(ctx.allocate(GcCell::new(Tree::Leaf(2))))
+        });
+        match &mut *pair.0.write(&ctx) {
+            Tree::Pair(left, right) => {
+                *left = Some(*a);
+                *right = Some(*b);
+            }
+            Tree::Leaf(_) => unreachable!(),
+        }
+    }
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 3);
+    let leaf = |tree: Option<TreeRef>| match *tree.unwrap().0.borrow(&ctx).borrow() {
+        Tree::Leaf(value) => value,
+        Tree::Pair(..) => unreachable!(),
+    };
+    match *pair.0.borrow(&ctx).borrow() {
+        Tree::Pair(left, right) => assert_eq!((leaf(left), leaf(right)), (1, 2)),
+        Tree::Leaf(_) => unreachable!(),
+    };
+}
+
+#[test]
+fn test_collect_if_needed() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_pacing(GcPacing {
+        step_size: 1024,
+        ..Default::default()
+    });
+    let object = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("Test".to_string()));
+    let size = ctx.allocated_bytes();
+    assert_eq!(ctx.allocated_objects(), 1);
+
+    let mut cycle_started = false;
+    for i in 0..100_000 {
+        ctx.allocate(i);
+        ctx.collect_if_needed();
+        cycle_started |= ctx.phase() != GcPhase::Idle;
+    }
+    assert!(cycle_started);
+    assert!(ctx.allocated_objects() < 100_000);
+
+    // The first call only finishes the paced cycle.
+    ctx.collect();
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 1);
+    assert_eq!(ctx.allocated_bytes(), size);
+    assert_eq!(*object.borrow(&ctx), "Test");
+}
+
+#[derive(Gc)]
+struct Medium([u8; 2100]);
+
+#[derive(Gc)]
+#[repr(align(64))]
+struct Aligned(u64);
+
+#[test]
+fn test_medium_objects() {
+    let mut ctx = GcContext::new().unwrap();
+    // Objects in separate pages would be at least a page apart.
+    let a = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(Medium([0; 2100])));
+    let b = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(Medium([0; 2100])));
+    assert!((a.as_ptr() as usize).abs_diff(b.as_ptr() as usize) < 64 * 1024);
+
+    let c = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(Aligned(1)));
+    let d = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(Aligned(2)));
+    assert_eq!(c.as_ptr() as usize % 64, 0);
+    assert_eq!(d.as_ptr() as usize % 64, 0);
+    assert!((c.as_ptr() as usize).abs_diff(d.as_ptr() as usize) < 64 * 1024);
+    assert_eq!(d.borrow(&ctx).0, 2);
+}
+
+#[test]
+fn test_multiple_contexts() {
+    let mut ctx1 = GcContext::new().unwrap();
+    let mut ctx2 = GcContext::new().unwrap();
+    let object1 = GcHeapRoot::new(&mut ctx1, |ctx| ctx.allocate("One".to_string()));
+    let object2 = GcHeapRoot::new(&mut ctx2, |ctx| ctx.allocate("Two".to_string()));
+    ctx1.allocate(1);
+    ctx2.allocate(2);
+
+    ctx1.collect();
+    assert_eq!(ctx1.allocated_objects(), 1);
+    assert_eq!(ctx2.allocated_objects(), 2);
+    ctx2.collect();
+    assert_eq!(*object1.borrow(&ctx1), "One");
+    assert_eq!(*object2.borrow(&ctx2), "Two");
+
+    drop(object1);
+    ctx1.destroy();
+    let mut ctx3 = GcContext::new().unwrap();
+    let object3 = GcHeapRoot::new(&mut ctx3, |ctx| ctx.allocate("Three".to_string()));
+    ctx3.collect();
+    assert_eq!(*object3.borrow(&ctx3), "Three");
+    assert_eq!(*object2.borrow(&ctx2), "Two");
+}
+
+// Roots are only checked for foreign objects in debug builds.
+#[cfg(any(debug_assertions, feature = "verify"))]
+#[test]
+#[should_panic(expected = "was used with a context other than the one that allocated it")]
+fn test_foreign_root() {
+    let mut ctx1 = GcContext::new().unwrap();
+    let mut ctx2 = GcContext::new().unwrap();
+    let object = GcHeapRoot::new(&mut ctx1, |ctx| ctx.allocate(1u32));
+    // The root would otherwise keep referencing the object after `ctx1` freed it.
+    GcHeapRoot::new(&mut ctx2, |_| *object);
+}
+
+#[test]
+#[should_panic(expected = "Pinned a root with a different context than it is registered with")]
+fn test_repin_other_context() {
+    let ctx1 = GcContext::new().unwrap();
+    let ctx2 = GcContext::new().unwrap();
+    let mut root = unsafe { GcRoot::new(1u32) };
+    root.pin(&ctx1);
+    root.pin(&ctx2);
+}
+
+#[test]
+#[should_panic(expected = "was used with a context other than the one that allocated it")]
+fn test_foreign_downgrade() {
+    let mut ctx1 = GcContext::new().unwrap();
+    let ctx2 = GcContext::new().unwrap();
+    let object = GcHeapRoot::new(&mut ctx1, |ctx| ctx.allocate(1u32));
+    object.downgrade(&ctx2);
+}
+
+#[test]
+#[should_panic(expected = "was used with a context other than the one that created it")]
+fn test_foreign_upgrade() {
+    let mut ctx1 = GcContext::new().unwrap();
+    let mut ctx2 = GcContext::new().unwrap();
+    let object1 = GcHeapRoot::new(&mut ctx1, |ctx| ctx.allocate("One".to_string()));
+    let object2 = GcHeapRoot::new(&mut ctx2, |ctx| ctx.allocate(2u64));
+    object2.downgrade(&ctx2);
+    // The weak table of `ctx2` has `object2` in the slot that `weak` refers to in `ctx1`.
+    let weak = object1.downgrade(&ctx1);
+    weak.upgrade(&ctx2);
+}
+
+#[test]
+fn test_collect_minor() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_nursery(Some(GcNursery {
+        promotion_age: 1,
+        ..Default::default()
+    }));
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    for i in 0..10 {
+        ctx.allocate(i);
+    }
+    assert_eq!(ctx.allocated_objects(), 11);
+
+    // Garbage is freed and `a` is promoted.
+    ctx.collect_minor();
+    assert_eq!(ctx.allocated_objects(), 1);
+
+    // A young object only reachable from an old one is kept alive by the remembered set.
+    {
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*b);
+    }
+    ctx.collect_minor();
+    assert_eq!(ctx.allocated_objects(), 2);
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 0);
+
+    a.0.write(&ctx).other = None;
+    ctx.collect_minor();
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 1);
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 1);
+}
+
+#[test]
+fn test_remembered_set() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_nursery(Some(GcNursery {
+        promotion_age: 2,
+        ..Default::default()
+    }));
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    ctx.collect_minor();
+    ctx.collect_minor();
+    {
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*b);
+    }
+    ctx.collect_minor();
+    assert_eq!(ctx.stats().remembered_objects, 1);
+
+    // Once `b` is promoted, `a` no longer references anything young, even though the nursery
+    // isn't empty.
+    let _c = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(0u32));
+    ctx.collect_minor();
+    assert_eq!(ctx.stats().remembered_objects, 0);
+
+    // Dead objects are dropped from the remembered set by a major collection.
+    {
+        let d = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*d);
+    }
+    assert_eq!(ctx.stats().remembered_objects, 1);
+    drop(a);
+    ctx.collect();
+    assert_eq!(ctx.stats().remembered_objects, 0);
+    assert_eq!(ctx.allocated_objects(), 1);
+}
+
+#[derive(Gc)]
+#[gc(finalize)]
+struct Finalizable<'a> {
+    child: CellNode<'a>,
+}
+
+impl Finalize for Finalizable<'_> {
+    fn finalize(this: Gc<'_, Self>, ctx: &mut GcContext) {
+        // The child must still be alive, since it is only reachable from this object.
+        let child = this.borrow(ctx).child;
+        assert!(child.other(ctx).is_none());
+        assert_eq!(CELL_NODE_DROPS.with(Cell::get), 0);
+        FINALIZED.with(|finalized| finalized.set(finalized.get() + 1));
+    }
+}
+
+#[derive(Gc)]
+struct Handle(u32);
+
+impl Finalize for Handle {
+    fn finalize(this: Gc<'_, Self>, ctx: &mut GcContext) {
+        assert_eq!(this.borrow(ctx).0, 42);
+        FINALIZED.with(|finalized| finalized.set(finalized.get() + 1));
+    }
+}
+
+#[derive(Gc)]
+struct Faulty(u32);
+
+impl Finalize for Faulty {
+    fn finalize(_: Gc<'_, Self>, _: &mut GcContext) {
+        panic!("Faulty finalizer");
+    }
+}
+
+#[test]
+fn test_finalizer_panic() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.allocate_with_finalizer(Faulty(0));
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| ctx.collect()));
+    assert!(result.is_err());
+
+    // Later finalizers still run, and the one that panicked isn't retried.
+    ctx.allocate_with_finalizer(Handle(42));
+    ctx.collect();
+    assert_eq!(FINALIZED.with(Cell::get), 1);
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 0);
+}
+
+#[test]
+fn test_finalize() {
+    let mut ctx = GcContext::new().unwrap();
+    {
+        let child = GcHeapRoot::new(&mut ctx, CellNode::new);
+        ctx.allocate(Finalizable { child: *child });
+    }
+
+    // The finalizer runs after the first collection, and the objects are freed by the second.
+    ctx.collect();
+    assert_eq!(FINALIZED.with(Cell::get), 1);
+    assert_eq!(ctx.allocated_objects(), 2);
+    ctx.collect();
+    assert_eq!(FINALIZED.with(Cell::get), 1);
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 1);
+    assert_eq!(ctx.allocated_objects(), 0);
+
+    ctx.allocate_with_finalizer(Handle(42));
+    ctx.collect();
+    assert_eq!(FINALIZED.with(Cell::get), 2);
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 0);
+}
+
+fn cleanup(held: Gc<'_, u32>, ctx: &mut GcContext) {
+    let held = held.get(ctx);
+    CLEANED_UP.with(|cleaned_up| cleaned_up.set(cleaned_up.get() + held));
+}
+
+#[test]
+fn test_register_cleanup() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+    {
+        let held = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(1u32));
+        ctx.register_cleanup(a.0, *held, cleanup);
+        let held = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(10u32));
+        let token = ctx.register_cleanup(b.0, *held, cleanup);
+        assert!(ctx.unregister_cleanup(token));
+        assert!(!ctx.unregister_cleanup(token));
+    }
+
+    // The held value is kept alive until its callback has run.
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 3);
+    assert_eq!(CLEANED_UP.with(Cell::get), 0);
+
+    drop(a);
+    drop(b);
+    ctx.collect();
+    assert_eq!(CLEANED_UP.with(Cell::get), 1);
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 0);
+}
+
+#[derive(Gc, Clone, Copy)]
+struct Dictionary<'a>(Gc<'a, DictionaryData<'a>>);
+
+#[derive(Gc)]
+struct DictionaryData<'a> {
+    map: GcEphemeronMap<'a, String, Entry<'a>>,
+}
+
+#[derive(Gc, Clone, Copy)]
+struct Entry<'a>(Gc<'a, EntryData<'a>>);
+
+#[derive(Gc)]
+struct EntryData<'a> {
+    key: Gc<'a, String>,
+}
+
+#[test]
+fn test_ephemeron_map() {
+    let mut ctx = GcContext::new().unwrap();
+    let dict = GcHeapRoot::new(&mut ctx, |ctx| {
+        Dictionary(ctx.allocate(DictionaryData {
+            map: GcEphemeronMap::new(),
+        }))
+    });
+    let a = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("a".to_string()));
+    {
+        // Each value references its own key.
+        let b = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("b".to_string()));
+        for key in [*a, *b] {
+            let value = GcHeapRoot::new(&mut ctx, |ctx| Entry(ctx.allocate(EntryData { key })));
+            dict.0.borrow_mut(&mut ctx).map.insert(key, *value);
+        }
+    }
+    assert_eq!(ctx.allocated_objects(), 5);
+
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 3);
+    let map = &dict.0.borrow(&ctx).map;
+    assert_eq!(map.len(), 1);
+    assert!(map.get(*a).unwrap().0.borrow(&ctx).key.ptr_eq(*a));
+
+    drop(a);
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 1);
+    assert!(dict.0.borrow(&ctx).map.is_empty());
+}
+
+#[derive(Gc, Clone, Copy)]
+struct KeySet<'a>(Gc<'a, KeySetData<'a>>);
+
+#[derive(Gc)]
+struct KeySetData<'a> {
+    map: GcEphemeronMap<'a, String, u32>,
+}
+
+#[test]
+fn test_ephemeron_map_minor() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_nursery(Some(GcNursery {
+        promotion_age: 3,
+        ..Default::default()
+    }));
+    let set = GcHeapRoot::new(&mut ctx, |ctx| {
+        KeySet(ctx.allocate(KeySetData {
+            map: GcEphemeronMap::new(),
+        }))
+    });
+    for _ in 0..3 {
+        ctx.collect_minor();
+    }
+    {
+        let key = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("key".to_string()));
+        set.0.borrow_mut(&mut ctx).map.insert(*key, 1);
+
+        // The set references a young key, even though its values aren't managed.
+        ctx.collect_minor();
+        assert_eq!(ctx.stats().remembered_objects, 1);
+    }
+
+    // The key dies before it is promoted, and is removed from the old set.
+    ctx.collect_minor();
+    assert_eq!(ctx.allocated_objects(), 1);
+    assert!(set.0.borrow(&ctx).map.is_empty());
+    assert!(ctx.verify_heap().is_ok());
+}
+
+#[test]
+fn test_weak_value_map() {
+    let mut ctx = GcContext::new().unwrap();
+    let mut map = GcHeapRoot::new(&mut ctx, |ctx| GcWeakValueMap::<String, String>::new(ctx));
+    let a = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("a".to_string()));
+    {
+        let b = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("b".to_string()));
+        map.insert("a".to_string(), a.downgrade(&ctx));
+        map.insert("b".to_string(), b.downgrade(&ctx));
+    }
+    assert_eq!(map.len(), 2);
+
+    // The dead entry is removed by the collection, without needing to be looked up.
+    ctx.collect();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.pruned(), 1);
+    assert!(!map.contains_key("b"));
+    assert_eq!(*map.get(&ctx, "a").unwrap().borrow(&ctx), "a");
+
+    drop(a);
+    ctx.collect();
+    assert!(map.is_empty());
+    assert_eq!(map.iter(&ctx).count(), 0);
+}
+
+#[test]
+fn test_cleared_weaks() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_track_cleared_weaks(true);
+    let a = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("a".to_string()));
+    let weak_a = a.downgrade(&ctx).id();
+    let weak_b = {
+        let b = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("b".to_string()));
+        assert_eq!(b.downgrade(&ctx).id(), b.downgrade(&ctx).id());
+        b.downgrade(&ctx).id()
+    };
+    assert_ne!(weak_a, weak_b);
+
+    ctx.collect();
+    assert_eq!(ctx.cleared_weaks(), [weak_b]);
+    assert!(a.downgrade(&ctx).is_alive(&ctx));
+
+    ctx.collect();
+    assert!(ctx.cleared_weaks().is_empty());
+    ctx.set_track_cleared_weaks(false);
+    let weak = a.downgrade(&ctx);
+    drop(a);
+    ctx.collect();
+    assert!(weak.is_dead(&ctx));
+    assert!(ctx.cleared_weaks().is_empty());
+}
+
+#[test]
+fn test_stats() {
+    let mut ctx = GcContext::new().unwrap();
+    assert_eq!(ctx.stats(), GcStats::default());
+    let object = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("Test".to_string()));
+    let size = ctx.allocated_bytes();
+    object.downgrade(&ctx);
+    for i in 0..10u64 {
+        ctx.allocate(i);
+    }
+    let peak = ctx.allocated_bytes();
+
+    ctx.collect();
+    let stats = ctx.stats();
+    assert_eq!(stats.collections, 1);
+    assert_eq!(stats.live_objects, 1);
+    assert_eq!(stats.live_bytes, size);
+    assert_eq!(stats.peak_bytes, peak);
+    assert_eq!(stats.freed_objects, 10);
+    assert_eq!(stats.freed_bytes, peak - size);
+    assert_eq!(stats.roots, 1);
+    assert_eq!(stats.weak_entries, 1);
+
+    drop(object);
+    ctx.set_nursery(Some(GcNursery::default()));
+    ctx.collect_minor();
+    let stats = ctx.stats();
+    assert_eq!((stats.collections, stats.minor_collections), (1, 1));
+    assert_eq!(stats.freed_objects, 0);
+    assert_eq!(stats.roots, 0);
+}
+
+#[test]
+fn test_stress_interval() {
+    let mut ctx = GcContext::new().unwrap();
+    ctx.set_stress_interval(NonZeroU32::new(3));
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    {
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*b);
+    }
+    for i in 0..10 {
+        ctx.allocate(i);
+    }
+
+    // Every third allocation collects the garbage allocated before it.
+    assert_eq!(ctx.stats().collections, 4);
+    assert_eq!(ctx.allocated_objects(), 3);
+    assert!(a.other(&ctx).is_some());
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 0);
+
+    ctx.set_stress_interval(None);
+    ctx.allocate(10);
+    assert_eq!(ctx.stats().collections, 4);
+}
+
+#[test]
+fn test_heap_snapshot() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    {
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*b);
+    }
+    ctx.collect();
+
+    let snapshot = ctx.heap_snapshot();
+    let a_id = a.0.as_ptr() as usize;
+    let b_id = a.other(&ctx).unwrap().0.as_ptr() as usize;
+    assert_eq!(snapshot.roots.len(), 1);
+    assert_eq!(snapshot.roots[0].kind, GcRootKind::Heap);
+    assert_eq!(snapshot.roots[0].type_name, "tests::CellNode<'_>");
+    assert_eq!(snapshot.roots[0].edges, [a_id]);
+    assert_eq!(snapshot.objects.len(), 2);
+    let a_info = snapshot.objects.iter().find(|o| o.id == a_id).unwrap();
+    assert!(a_info
+        .type_name
+        .ends_with("GcCell<tests::CellNodeData<'_>>"));
+    assert_eq!(a_info.size, ctx.allocated_bytes() / 2);
+    assert_eq!(a_info.edges, [b_id]);
+
+    let mut dot = Vec::new();
+    snapshot.write_dot(&mut dot).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.starts_with("digraph heap {"));
+    assert!(dot.contains(&format!("obj{:x} -> obj{:x};", a_id, b_id)));
+
+    let mut json = Vec::new();
+    snapshot.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains(&format!("\"edges\":[{}]", b_id)));
+    assert!(json.contains(&format!(
+        "\"roots\":[{{\"kind\":\"heap\",\"type\":\"tests::CellNode<'_>\",\"edges\":[{}]}}]",
+        a_id
+    )));
+
+    // The synthetic root, four root groups, the root and both objects.
+    let mut heapsnapshot = Vec::new();
+    snapshot.write_heapsnapshot(&mut heapsnapshot).unwrap();
+    let heapsnapshot = String::from_utf8(heapsnapshot).unwrap();
+    assert!(heapsnapshot.contains("\"node_count\":8,\"edge_count\":7,"));
+    assert!(heapsnapshot.contains("\"(GcHeapRoot roots)\""));
+}
+
+#[test]
+fn test_snapshot_exports() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    {
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        let c = GcHeapRoot::new(&mut ctx, CellNode::new);
+        a.0.write(&ctx).other = Some(*b);
+        b.0.write(&ctx).other = Some(*c);
+    }
+    // Unreachable, but not yet collected.
+    ctx.allocate(1u32);
+    let snapshot = ctx.heap_snapshot();
+    assert_eq!(snapshot.objects.len(), 4);
+
+    let mut json = Vec::new();
+    snapshot.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let objects = json["objects"].as_array().unwrap();
+    assert_eq!(objects.len(), 4);
+    let edge_count: usize = objects
+        .iter()
+        .map(|object| object["edges"].as_array().unwrap().len())
+        .sum();
+    assert_eq!(edge_count, 2);
+    let roots = json["roots"].as_array().unwrap();
+    assert_eq!(roots.len(), 1);
+    assert_eq!(roots[0]["kind"], "heap");
+    assert_eq!(roots[0]["edges"][0], a.0.as_ptr() as usize);
+
+    let mut heapsnapshot = Vec::new();
+    snapshot.write_heapsnapshot(&mut heapsnapshot).unwrap();
+    let heapsnapshot: serde_json::Value = serde_json::from_slice(&heapsnapshot).unwrap();
+    let to_vec = |value: &serde_json::Value| -> Vec<usize> {
+        let array = value.as_array().unwrap();
+        array.iter().map(|n| n.as_u64().unwrap() as usize).collect()
+    };
+    let nodes = to_vec(&heapsnapshot["nodes"]);
+    let edges = to_vec(&heapsnapshot["edges"]);
+    let strings = heapsnapshot["strings"].as_array().unwrap();
+    let meta = &heapsnapshot["snapshot"];
+    let node_fields = meta["meta"]["node_fields"].as_array().unwrap().len();
+    let edge_fields = meta["meta"]["edge_fields"].as_array().unwrap().len();
+    assert_eq!(nodes.len() % node_fields, 0);
+    assert_eq!(edges.len() % edge_fields, 0);
+
+    // The synthetic root, four root groups, the root and four objects.
+    assert_eq!(meta["node_count"], 10);
+    assert_eq!(nodes.len() / node_fields, 10);
+    // One edge to each root group, one to the root, and one for each reference.
+    assert_eq!(meta["edge_count"], 8);
+    assert_eq!(edges.len() / edge_fields, 8);
+    let edge_counts: usize = nodes.chunks(node_fields).map(|node| node[4]).sum();
+    assert_eq!(edge_counts, 8);
+    for node in nodes.chunks(node_fields) {
+        assert!(node[1] < strings.len());
+    }
+    for edge in edges.chunks(edge_fields) {
+        assert_eq!(edge[2] % node_fields, 0);
+        assert!(edge[2] < nodes.len());
+    }
+}
+
+#[test]
+fn test_snapshot_diff() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate("a".to_string()));
+    // Keeps the garbage's page from being released.
+    let _neighbour = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(0u64));
+    let garbage = ctx.allocate(1u64).as_ptr() as usize;
+    let before = ctx.object_snapshot();
+    assert_eq!(before.objects.len(), 3);
+
+    // The new object reuses the garbage's slot, but isn't mistaken for it. Freed slots are
+    // quarantined instead of reused with the `poison` feature.
+    ctx.collect();
+    let b = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(2u64));
+    if cfg!(not(feature = "poison")) {
+        assert_eq!(b.as_ptr() as usize, garbage);
+    }
+    let c = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(3u64));
+    let after = ctx.object_snapshot();
+
+    let diff = before.diff(&after);
+    assert_eq!((diff.new_objects, diff.freed_objects), (2, 1));
+    assert_eq!(diff.object_growth(), 1);
+    assert_eq!(
+        diff.byte_growth(),
+        3 * std::mem::size_of::<usize>() as isize
+    );
+    assert_eq!(diff.types.len(), 1);
+    assert_eq!(diff.types[0].type_name, "u64");
+    assert_eq!(diff.types[0].object_growth(), 1);
+    assert!(after.diff(&ctx.object_snapshot()).types.is_empty());
+    drop((a, b, c));
+}
+
+#[derive(Gc)]
+struct Flaky<'a>(Gc<'a, FlakyData<'a>>);
+
+/// A broken `Trace` implementation that only traces its child once `traced` is set.
+struct FlakyData<'a> {
+    child: Gc<'a, u32>,
+    traced: Cell<bool>,
+}
+
+unsafe impl Trace for FlakyData<'_> {
+    unsafe fn trace(&self, ctx: &mut GcContext) {
+        if self.traced.get() {
+            self.child.trace(ctx);
+        }
+    }
+}
+
+unsafe impl<'a> GcLifetime<'a> for FlakyData<'_> {
+    type Aged = FlakyData<'a>;
+}
+
+#[test]
+fn test_verify_heap() {
+    let mut ctx = GcContext::new().unwrap();
+    let child = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(1u32));
+    let flaky = GcHeapRoot::new(&mut ctx, |ctx| {
+        Flaky(ctx.allocate(FlakyData {
+            child: *child,
+            traced: Cell::new(false),
+        }))
+    });
+    drop(child);
+    assert_eq!(ctx.verify_heap(), Ok(()));
+
+    // The child is freed while it is still referenced.
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 1);
+    flaky.0.borrow(&ctx).traced.set(true);
+    let error = ctx.verify_heap().unwrap_err();
+    assert_eq!(error.problems.len(), 1);
+    assert!(error.problems[0].contains("isn't an allocated object"));
+    flaky.0.borrow(&ctx).traced.set(false);
+}
+
+#[test]
+fn test_arena() {
+    fn new_node<'gc>(mc: &GcMutation<'gc>) -> CellNode<'gc> {
+        CellNode(mc.allocate(GcCell::new(CellNodeData { other: None })))
+    }
+
+    let mut arena = GcArena::<CellNode<'static>>::new(new_node).unwrap();
+    arena.mutate(|mc, root| {
+        // Nothing allocated in a session needs to be rooted.
+        let a = new_node(mc);
+        let b = new_node(mc);
+        a.0.write(mc).other = Some(b);
+        root.0.write(mc).other = Some(a);
+        mc.allocate(1u32);
+    });
+    arena.collect();
+    assert_eq!(arena.context().allocated_objects(), 3);
+
+    let b_linked = arena.mutate(|mc, root| {
+        let a = root.other(mc).unwrap();
+        a.other(mc).is_some()
+    });
+    assert!(b_linked);
+    arena.mutate(|mc, root| root.0.write(mc).other = None);
+    arena.collect();
+    assert_eq!(arena.context().allocated_objects(), 1);
+    assert_eq!(CELL_NODE_DROPS.with(Cell::get), 2);
+}
+
+#[test]
+fn test_dynamic_roots() {
+    let mut ctx = GcContext::new().unwrap();
+    let roots = GcDynamicRootSet::new(&mut ctx);
+    let (a, b): (GcDynamicRoot<GcCell<CellNodeData<'static>>>, _) = {
+        let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+        let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+        (roots.stash(&ctx, a.0), roots.stash(&ctx, b.0))
+    };
+    let a2 = a.clone();
+    assert!(a.ptr_eq(&a2) && !a.ptr_eq(&b));
+    assert_eq!(roots.len(), 2);
+
+    // Handles don't borrow the context, and keep their objects alive until the last clone drops.
+    a.fetch(&ctx).write(&ctx).other = Some(CellNode(b.fetch(&ctx)));
+    drop(b);
+    drop(a);
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 2);
+    assert!(CellNode(a2.fetch(&ctx)).other(&ctx).is_some());
+
+    drop(a2);
+    assert!(roots.is_empty());
+    ctx.collect();
+    assert_eq!(ctx.allocated_objects(), 0);
+    drop(roots);
+    ctx.destroy();
+}
+
+#[test]
+#[should_panic(expected = "was used with a context other than the one that allocated it")]
+fn test_foreign_dynamic_root() {
+    let mut ctx1 = GcContext::new().unwrap();
+    let mut ctx2 = GcContext::new().unwrap();
+    let roots = GcDynamicRootSet::new(&mut ctx1);
+    let object = GcHeapRoot::new(&mut ctx2, |ctx| ctx.allocate(1u32));
+    roots.stash(&ctx1, *object);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_scan_stack() {
+    let mut ctx = GcContext::new().unwrap();
+    // The object is only held in a local.
+    unsafe { ctx.set_scan_stack(true) };
+    let object: Gc<'_, String> = unsafe { ctx.allocate("Test".to_string()).change_lifetime() };
+    let weak = object.downgrade(&ctx);
+    ctx.collect();
+    ctx.set_nursery(Some(GcNursery::default()));
+    ctx.allocate(1u32);
+    ctx.collect_minor();
+
+    // The unrooted object is only referenced from the stack.
+    assert!(weak.is_alive(&ctx));
+    assert_eq!(*std::hint::black_box(object).borrow(&ctx), "Test");
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_check_traces() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+    a.0.write(&ctx).other = Some(*b);
+    let child = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(1u32));
+    let flaky = GcHeapRoot::new(&mut ctx, |ctx| {
+        Flaky(ctx.allocate(FlakyData {
+            child: *child,
+            traced: Cell::new(true),
+        }))
+    });
+    assert_eq!(ctx.check_traces(), vec![]);
+
+    flaky.0.borrow(&ctx).traced.set(false);
+    let untraced = ctx.check_traces();
+    assert_eq!(untraced.len(), 1);
+    assert_eq!(untraced[0].id, flaky.0.as_ptr() as usize);
+    assert_eq!(untraced[0].offset, std::mem::offset_of!(FlakyData, child));
+    assert_eq!(untraced[0].target_id, child.as_ptr() as usize);
+    assert_eq!(untraced[0].target_type_name, "u32");
+}
+
+#[cfg(feature = "verify")]
+#[test]
+#[should_panic(expected = "which isn't an allocated object")]
+fn test_verify_incremental() {
+    let mut ctx = GcContext::new().unwrap();
+    let child = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(1u32));
+    let flaky = GcHeapRoot::new(&mut ctx, |ctx| {
+        Flaky(ctx.allocate(FlakyData {
+            child: *child,
+            traced: Cell::new(false),
+        }))
+    });
+    drop(child);
+    while ctx.phase() != GcPhase::Sweeping {
+        ctx.collect_step(1);
+    }
+    // The child wasn't marked, and is reported once the sweep has freed it.
+    flaky.0.borrow(&ctx).traced.set(true);
+    while ctx.phase() != GcPhase::Idle {
+        ctx.collect_step(1);
+    }
+}
+
+#[cfg(feature = "poison")]
+#[test]
+#[should_panic(expected = "Use of a freed `u32`")]
+fn test_poison() {
+    let mut ctx = GcContext::new().unwrap();
+    let child = GcHeapRoot::new(&mut ctx, |ctx| ctx.allocate(1u32));
+    let flaky = GcHeapRoot::new(&mut ctx, |ctx| {
+        Flaky(ctx.allocate(FlakyData {
+            child: *child,
+            traced: Cell::new(false),
+        }))
+    });
+    drop(child);
+    ctx.collect();
+    flaky.0.borrow(&ctx).child.get(&ctx);
+}
+
+#[derive(Gc)]
+enum Slot<'a> {
+    Pair {
+        left: CellNode<'a>,
+        right: CellNode<'a>,
+    },
+}
+
+#[test]
+fn test_retaining_paths() {
+    let mut ctx = GcContext::new().unwrap();
+    let a = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let b = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let slot = GcHeapRoot::new(&mut ctx, |_| Slot::Pair {
+        left: *b,
+        right: *b,
+    });
+    a.0.write(&ctx).other = Some(*b);
+    drop(b);
+
+    // Both paths through the slot are shorter than the one through `a`.
+    let b = a.other(&ctx).unwrap();
+    let paths = ctx.retaining_paths(b.0, 10);
+    assert_eq!(paths.len(), 2);
+    assert!(paths.iter().all(|path| path.root_kind == GcRootKind::Heap));
+    let mut fields: Vec<_> = paths.iter().map(|path| path.hops[0].field).collect();
+    fields.sort();
+    assert_eq!(fields, [Some("Pair::left"), Some("Pair::right")]);
+    assert_eq!(ctx.retaining_paths(b.0, 1).len(), 1);
+
+    drop(slot);
+    let b = a.other(&ctx).unwrap();
+    let paths = ctx.retaining_paths(b.0, 10);
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].hops.len(), 2);
+    assert_eq!(paths[0].hops[1].id, b.0.as_ptr() as usize);
+    assert_eq!(
+        paths[0].to_string(),
+        "heap root tests::CellNode<'_> -> .0 ruffle_gc::cell::GcCell<tests::CellNodeData<'_>> \
+         -> .other ruffle_gc::cell::GcCell<tests::CellNodeData<'_>>"
+    );
+
+    // Unreachable objects that haven't been collected yet have no retainers.
+    let c = GcHeapRoot::new(&mut ctx, CellNode::new);
+    let weak = c.0.downgrade(&ctx);
+    drop(c);
+    let c = weak.upgrade(&ctx).unwrap();
+    assert!(ctx.retaining_paths(c, 10).is_empty());
+
+    // Neither have objects that have been collected, which can still be asked about through weak
+    // pointers.
+    let weak = GcHeapRoot::new(&mut ctx, CellNode::new).0.downgrade(&ctx);
+    ctx.collect();
+    assert!(weak.retaining_paths(&ctx, 10).is_empty());
+}
+
+#[test]
+fn test_long_retaining_path() {
+    fn new_node<'gc>(mc: &GcMutation<'gc>) -> CellNode<'gc> {
+        CellNode(mc.allocate(GcCell::new(CellNodeData { other: None })))
+    }
+
+    let mut arena = GcArena::<CellNode<'static>>::new(new_node).unwrap();
+    let hops = arena.mutate(|mc, root| {
+        let ctx = mc.context();
+        let mut tail = *root;
+        for _ in 0..10_000 {
+            let next = new_node(mc);
+            tail.0.write(ctx).other = Some(next);
+            tail = next;
+        }
+        let paths = ctx.retaining_paths(tail.0, 10);
+        assert_eq!(paths.len(), 1);
+        paths[0].hops.len()
+    });
+    assert_eq!(hops, 10_001);
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    CycleStart(GcCollection),
+    CycleEnd(GcCollection),
+    Phase(GcPhase),
+    Allocate(*const ()),
+    Free(*const ()),
+}
+
+struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+impl GcObserver for Recorder {
+    fn cycle_start(&mut self, collection: GcCollection, _number: u32) {
+        self.0.borrow_mut().push(Event::CycleStart(collection));
+    }
+
+    fn cycle_end(&mut self, collection: GcCollection, _number: u32) {
+        self.0.borrow_mut().push(Event::CycleEnd(collection));
+    }
+
+    fn phase_change(&mut self, phase: GcPhase) {
+        self.0.borrow_mut().push(Event::Phase(phase));
+    }
+
+    fn allocate(&mut self, ptr: *const (), _size: usize) {
+        self.0.borrow_mut().push(Event::Allocate(ptr));
+    }
+
+    fn free(&mut self, ptr: *const (), _size: usize) {
+        self.0.borrow_mut().push(Event::Free(ptr));
+    }
+}
+
+#[test]
+fn test_observer() {
+    let mut ctx = GcContext::new().unwrap();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    assert!(ctx
+        .set_observer(Some(Box::new(Recorder(events.clone()))))
+        .is_none());
+
+    let object = ctx.allocate(1u32).as_ptr() as *const ();
+    ctx.collect();
+    assert_eq!(
+        *events.borrow(),
+        [
+            Event::Allocate(object),
+            Event::CycleStart(GcCollection::Major),
+            Event::Phase(GcPhase::Marking),
+            Event::Phase(GcPhase::Sweeping),
+            Event::Free(object),
+            Event::Phase(GcPhase::Idle),
+            Event::CycleEnd(GcCollection::Major),
+        ]
+    );
+
+    events.borrow_mut().clear();
+    ctx.set_nursery(Some(GcNursery::default()));
+    ctx.collect_minor();
+    assert_eq!(
+        *events.borrow(),
+        [
+            Event::CycleStart(GcCollection::Minor),
+            Event::CycleEnd(GcCollection::Minor),
+        ]
+    );
+
+    assert!(ctx.set_observer(None).is_some());
+    ctx.allocate(1u32);
+    assert_eq!(events.borrow().len(), 2);
+}
+
 #[test]
 fn compile_fails() {
     let t = trybuild::TestCases::new();
     t.compile_fail("tests/compile_fails/allocate.rs");
+    t.compile_fail("tests/compile_fails/arena.rs");
     t.compile_fail("tests/compile_fails/borrow_mut.rs");
+    t.compile_fail("tests/compile_fails/cell.rs");
 }
@@ -1,8 +1,8 @@
 use proc_macro2::Span;
 use quote::quote;
-use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Ident};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Ident, Meta, NestedMeta};
 
-#[proc_macro_derive(Gc)]
+#[proc_macro_derive(Gc, attributes(gc))]
 pub fn gc(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let mut generics = input.generics.clone();
@@ -23,72 +23,23 @@ pub fn gc(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         quote! {}
     };
 
-    let ty_name = &input.ident;
-    let trace_calls = match &input.data {
-        Data::Struct(data) => trace_fields(&data.fields),
-        Data::Enum(data) => {
-            let trace_variants: Vec<_> = data
-                .variants
-                .iter()
-                .map(|variant| {
-                    let variant_name = &variant.ident;
-                    match variant.fields.clone() {
-                        Fields::Named(fields) => {
-                            let ctor_fields: Vec<_> = fields
-                                .named
-                                .iter()
-                                .map(|field| {
-                                    let name = &field.ident;
-                                    quote! { #name }
-                                })
-                                .collect();
-                            let trace_fields: Vec<_>   = fields
-                            .named
-                            .iter()
-                            .map(|field| {
-                                let name = &field.ident;
-                                quote! { #name.trace(ctx); }
-                            })
-                            .collect();
-                            quote! { #ty_name::#variant_name { #(#ctor_fields),* } => { #( #trace_fields ),* } }
-                        }
-                        Fields::Unnamed(fields) => {
-                            let ctor_fields: Vec<_> = fields
-                                .unnamed
-                                .iter()
-                                .enumerate()
-                                .map(|(i, _)| {
-                                    let name = Ident::new(&format!("field{}", i), Span::call_site());
-                                    quote! { #name }
-                                })
-                                .collect();
-                                let trace_fields: Vec<_>   = fields
-                                .unnamed
-                                .iter()
-                                .enumerate()
-                                .map(|(i, _)| {
-                                    let name = Ident::new(&format!("field{}", i), Span::call_site());
-                                    quote! { #name.trace(ctx); }
-                                }).collect();
-                            quote! { #ty_name::#variant_name { #(#ctor_fields),* } => { #( #trace_fields ),* } }
-                        }
-                        Fields::Unit => {
-                            quote! { #ty_name::#variant_name => (), }
-                        }
-                    }
-                })
-                .collect();
-            quote! {
-                match self {
-                    #( #trace_variants )*
-                }
+    // `#[gc(finalize)]` registers the type's `Finalize` implementation.
+    let vtbl = if has_gc_attr(&input, "finalize") {
+        quote! {
+            fn vtbl() -> &'static ruffle_gc::GcVtbl {
+                ruffle_gc::GcVtbl::finalizable::<Self>()
             }
         }
-        Data::Union(_) => panic!("Unions not supported by #[derive(Gc)]"),
+    } else {
+        quote! {}
     };
 
+    let trace_calls = trace_body(&input, false);
+    let trace_field_calls = trace_body(&input, true);
+
     let gc_lifetime_impl = lifetime(&input);
 
+    let ty_name = &input.ident;
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     let output = quote! {
         unsafe impl #impl_generics ruffle_gc::Trace for #ty_name #ty_generics #where_clause {
@@ -96,7 +47,13 @@ pub fn gc(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 #trace_calls
             }
 
+            unsafe fn trace_fields(&self, ctx: &mut ruffle_gc::GcContext) {
+                #trace_field_calls
+            }
+
             #needs_trace
+
+            #vtbl
         }
 
         #gc_lifetime_impl
@@ -105,34 +62,112 @@ pub fn gc(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     output.into()
 }
 
-fn trace_fields(fields: &Fields) -> proc_macro2::TokenStream {
-    let trace_calls: Vec<_> = match fields {
-        Fields::Unit => Vec::new(),
-        Fields::Unnamed(fields) => fields
-            .unnamed
-            .iter()
-            .enumerate()
-            .map(|(i, _field)| {
-                let i = syn::Index::from(i);
-                quote! {
-                    self.#i.trace(ctx);
+fn has_gc_attr(input: &DeriveInput, name: &str) -> bool {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("gc"))
+        .any(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested.iter().any(|nested| match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident(name) => true,
+                _ => panic!("Unknown #[gc] attribute"),
+            }),
+            _ => panic!("Expected #[gc(...)]"),
+        })
+}
+
+/// Generates the body of `Trace::trace`, or of `Trace::trace_fields` if `labels` is set.
+fn trace_body(input: &DeriveInput, labels: bool) -> proc_macro2::TokenStream {
+    let ty_name = &input.ident;
+    match &input.data {
+        Data::Struct(data) => {
+            let trace_calls: Vec<_> = data
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    let member = match &field.ident {
+                        Some(name) => quote! { #name },
+                        None => {
+                            let i = syn::Index::from(i);
+                            quote! { #i }
+                        }
+                    };
+                    let label = field_label(labels, None, &field.ident, i);
+                    quote! { #label self.#member.trace(ctx); }
+                })
+                .collect();
+            quote! {
+                #( #trace_calls )*
+            }
+        }
+        Data::Enum(data) => {
+            let trace_variants: Vec<_> = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_name = &variant.ident;
+                    let bindings: Vec<_> = variant
+                        .fields
+                        .iter()
+                        .enumerate()
+                        .map(|(i, field)| match &field.ident {
+                            Some(name) => name.clone(),
+                            None => Ident::new(&format!("field{}", i), Span::call_site()),
+                        })
+                        .collect();
+                    let trace_calls: Vec<_> = variant
+                        .fields
+                        .iter()
+                        .zip(&bindings)
+                        .enumerate()
+                        .map(|(i, (field, binding))| {
+                            let label = field_label(labels, Some(variant_name), &field.ident, i);
+                            quote! { #label #binding.trace(ctx); }
+                        })
+                        .collect();
+                    match &variant.fields {
+                        Fields::Named(_) => quote! {
+                            #ty_name::#variant_name { #( #bindings ),* } => { #( #trace_calls )* }
+                        },
+                        Fields::Unnamed(_) => quote! {
+                            #ty_name::#variant_name( #( #bindings ),* ) => { #( #trace_calls )* }
+                        },
+                        Fields::Unit => quote! { #ty_name::#variant_name => (), },
+                    }
+                })
+                .collect();
+            quote! {
+                match self {
+                    #( #trace_variants )*
                 }
-            })
-            .collect(),
-        Fields::Named(fields) => fields
-            .named
-            .iter()
-            .map(|field| {
-                let name = field.ident.clone().unwrap();
-                quote! { self.#name.trace(ctx); }
-            })
-            .collect(),
-    };
-    quote! {
-        #( #trace_calls )*
+            }
+        }
+        Data::Union(_) => panic!("Unions not supported by #[derive(Gc)]"),
     }
 }
 
+/// Generates a call labelling the references in a field, e.g. `name` or `Variant::0`.
+fn field_label(
+    labels: bool,
+    variant: Option<&Ident>,
+    name: &Option<Ident>,
+    index: usize,
+) -> proc_macro2::TokenStream {
+    if !labels {
+        return quote! {};
+    }
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => index.to_string(),
+    };
+    let label = match variant {
+        Some(variant) => format!("{}::{}", variant, name),
+        None => name,
+    };
+    quote! { ctx.set_edge_label(#label); }
+}
+
 fn lifetime(input: &syn::DeriveInput) -> proc_macro2::TokenStream {
     let ty_name = input.ident.clone();
     let mut generics = input.generics.clone();
@@ -145,7 +180,7 @@ fn lifetime(input: &syn::DeriveInput) -> proc_macro2::TokenStream {
                 type Aged = Self;
             }
         };
-        return out.into();
+        return out;
     } else if num_lifetimes > 1 {
         panic!("Don't know how to deal with multiple lifetimes")
     }